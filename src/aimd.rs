@@ -0,0 +1,364 @@
+use crate::{
+    rules::{self, DirectLimiter, Target},
+    GovernorConfigError, RateLimitClock, RateLimitKey,
+};
+use governor::Quota;
+use http_types::StatusCode;
+use std::{
+    collections::HashMap,
+    error::Error,
+    io,
+    num::NonZeroU32,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+/// An additive-increase/multiplicative-decrease controller adapting the rate of each key
+/// to how its server responds.
+///
+/// Each key starts at the default quota of the middleware. Every successful response
+/// raises its rate by the increase, while a 429 (too many requests) or 503 (service
+/// unavailable) response or a timeout multiplies it by the decrease, always keeping it
+/// between the floor and the ceiling.
+///
+/// # Example
+/// This adapts the rate of each host between 1 and 50 requests per second, starting at 10
+/// ```no_run
+/// use surf_governor::{Aimd, GovernorMiddleware};
+/// use surf::Client;
+///
+/// use governor::Quota;
+/// use std::num::NonZeroU32;
+///
+/// # fn main() -> Result<(), surf_governor::GovernorConfigError> {
+/// let quota = |count| Quota::per_second(NonZeroU32::new(count).unwrap());
+/// let governor = GovernorMiddleware::builder()
+///     .per_second(10)
+///     .aimd(Aimd::new(quota(1), quota(50)))
+///     .build()?;
+/// let client = Client::new().with(governor);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aimd {
+    floor: Quota,
+    ceiling: Quota,
+    increase: Option<Quota>,
+    decrease: f64,
+}
+
+impl Aimd {
+    /// Constructs a controller keeping the rate of each key between the rates of `floor`
+    /// and `ceiling`, raising it by the rate of `floor` and halving it by default.
+    #[must_use]
+    pub fn new(floor: Quota, ceiling: Quota) -> Self {
+        Self {
+            floor,
+            ceiling,
+            increase: None,
+            decrease: 0.5,
+        }
+    }
+
+    /// Raises the rate of a key by the rate of `quota` on every successful response.
+    #[must_use]
+    pub fn increase(mut self, quota: Quota) -> Self {
+        self.increase = Some(quota);
+        self
+    }
+
+    /// Multiplies the rate of a key by `factor`, between 0 and 1, when its server asks
+    /// to slow down.
+    #[must_use]
+    pub fn decrease(mut self, factor: f64) -> Self {
+        self.decrease = factor;
+        self
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), GovernorConfigError> {
+        if rate(self.floor) > rate(self.ceiling) {
+            return Err(GovernorConfigError::FloorAboveCeiling);
+        }
+        if !(self.decrease > 0.0 && self.decrease < 1.0) {
+            return Err(GovernorConfigError::InvalidDecrease);
+        }
+        Ok(())
+    }
+}
+
+/// Returns the number of requests per second `quota` replenishes.
+fn rate(quota: Quota) -> f64 {
+    1.0 / quota.replenish_interval().as_secs_f64()
+}
+
+/// How the server responded to a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Outcome {
+    Success,
+    Congestion,
+    Other,
+}
+
+impl Outcome {
+    pub(crate) fn of(res: &Result<surf::Response, http_types::Error>) -> Self {
+        match res {
+            Ok(res) => match res.status() {
+                StatusCode::TooManyRequests
+                | StatusCode::ServiceUnavailable
+                | StatusCode::RequestTimeout
+                | StatusCode::GatewayTimeout => Self::Congestion,
+                status if status.is_success() => Self::Success,
+                _ => Self::Other,
+            },
+            Err(err) if timed_out(err) => Self::Congestion,
+            Err(_) => Self::Other,
+        }
+    }
+}
+
+/// Returns whether `err` reports a timeout: a 408 status, or an error in its source chain
+/// that is an `io::Error` timing out or says it timed out, as the errors of the `isahc`
+/// and `async-std` backends of surf do.
+fn timed_out(err: &http_types::Error) -> bool {
+    if err.status() == StatusCode::RequestTimeout {
+        return true;
+    }
+    let mut source: Option<&(dyn Error + 'static)> = Some(err.as_ref());
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<io::Error>() {
+            if err.kind() == io::ErrorKind::TimedOut {
+                return true;
+            }
+        }
+        let message = err.to_string().to_ascii_lowercase();
+        if message.contains("timed out") || message.contains("timeout") {
+            return true;
+        }
+        source = err.source();
+    }
+    false
+}
+
+/// The current rate of a key and a limiter enforcing it.
+#[derive(Debug)]
+struct Controller<C: RateLimitClock> {
+    rate: f64,
+    limiter: Arc<DirectLimiter<C>>,
+}
+
+/// The adapted rates of every key.
+#[derive(Debug)]
+pub(crate) struct Adaptive<K: RateLimitKey, C: RateLimitClock> {
+    aimd: Aimd,
+    start: f64,
+    burst: NonZeroU32,
+    controllers: Mutex<HashMap<K, Controller<C>>>,
+    clock: C,
+}
+
+impl<K: RateLimitKey, C: RateLimitClock> Adaptive<K, C> {
+    /// Constructs controllers starting at the rate and burst of `quota`.
+    pub(crate) fn new(aimd: Aimd, quota: Quota, clock: C) -> Self {
+        Self {
+            aimd,
+            start: rate(quota).clamp(rate(aimd.floor), rate(aimd.ceiling)),
+            burst: quota.burst_size(),
+            controllers: Mutex::new(HashMap::new()),
+            clock,
+        }
+    }
+
+    /// Returns the same configuration without any adapted rates, for another type of key,
+    /// measuring time with `clock`.
+    pub(crate) fn rekey<L: RateLimitKey, D: RateLimitClock>(&self, clock: D) -> Adaptive<L, D> {
+        Adaptive {
+            aimd: self.aimd,
+            start: self.start,
+            burst: self.burst,
+            controllers: Mutex::new(HashMap::new()),
+            clock,
+        }
+    }
+
+    /// Returns the same configuration without any adapted rates, starting at the rate and
+    /// burst of `quota`.
+    pub(crate) fn requota(&self, quota: Quota) -> Self {
+        Self::new(self.aimd, quota, self.clock.clone())
+    }
+
+    /// Returns the limiter enforcing the current rate of `key`.
+    pub(crate) fn limiter(&self, key: &K) -> Arc<DirectLimiter<C>> {
+        let mut controllers = self
+            .controllers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(controller) = controllers.get(key) {
+            return controller.limiter.clone();
+        }
+        let limiter = rules::direct(self.quota(self.start), &self.clock);
+        let controller = Controller {
+            rate: self.start,
+            limiter: limiter.clone(),
+        };
+        controllers.insert(key.clone(), controller);
+        limiter
+    }
+
+    /// Adapts the rate of `key` to `outcome`.
+    ///
+    /// A raised rate is enforced by a new limiter with the cells the old one had left, so
+    /// that successes never make a key stricter. A lowered rate is enforced by a new
+    /// limiter without any burst capacity, so that it takes effect at once.
+    pub(crate) fn observe(&self, key: &K, outcome: Outcome) {
+        let mut controllers = self
+            .controllers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let controller = match controllers.get_mut(key) {
+            Some(controller) => controller,
+            None => return,
+        };
+        let (floor, ceiling) = (rate(self.aimd.floor), rate(self.aimd.ceiling));
+        let increase = rate(self.aimd.increase.unwrap_or(self.aimd.floor));
+        let adapted = match outcome {
+            Outcome::Success => controller.rate + increase,
+            Outcome::Congestion => controller.rate * self.aimd.decrease,
+            Outcome::Other => return,
+        }
+        .clamp(floor, ceiling);
+        if adapted == controller.rate {
+            return;
+        }
+        let used = match outcome {
+            Outcome::Success => {
+                let old = Target::<K, C>::Shared(controller.limiter.clone());
+                let (remaining, _) = old.state(self.quota(controller.rate));
+                NonZeroU32::new(self.burst.get() - remaining)
+            }
+            _ => Some(self.burst),
+        };
+        let limiter = rules::direct(self.quota(adapted), &self.clock);
+        if let Some(used) = used {
+            let _ = limiter.check_n(used);
+        }
+        *controller = Controller {
+            rate: adapted,
+            limiter,
+        };
+    }
+
+    fn quota(&self, rate: f64) -> Quota {
+        let period = Duration::from_secs_f64(1.0 / rate).max(Duration::from_nanos(1));
+        Quota::with_period(period)
+            .expect("period is not zero")
+            .allow_burst(self.burst)
+    }
+
+    #[cfg(test)]
+    fn rate(&self, key: &K) -> Option<f64> {
+        let controllers = self
+            .controllers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        controllers.get(key).map(|controller| controller.rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Adaptive, Aimd, Outcome};
+    use crate::GovernorConfigError;
+    use governor::{
+        clock::{DefaultClock, FakeRelativeClock},
+        Quota,
+    };
+    use std::{num::NonZeroU32, time::Duration};
+
+    fn quota(count: u32) -> Quota {
+        Quota::per_second(NonZeroU32::new(count).unwrap())
+    }
+
+    #[test]
+    fn validates_settings() {
+        assert_eq!(Aimd::new(quota(1), quota(10)).validate(), Ok(()));
+        assert_eq!(
+            Aimd::new(quota(10), quota(1)).validate(),
+            Err(GovernorConfigError::FloorAboveCeiling)
+        );
+        assert_eq!(
+            Aimd::new(quota(1), quota(10)).decrease(1.0).validate(),
+            Err(GovernorConfigError::InvalidDecrease)
+        );
+    }
+
+    #[test]
+    fn adapts_rate_within_bounds() {
+        let adaptive = Adaptive::new(
+            Aimd::new(quota(2), quota(10)),
+            quota(4),
+            DefaultClock::default(),
+        );
+        adaptive.limiter(&"host");
+        let rate = || adaptive.rate(&"host").unwrap().round();
+        assert_eq!(rate(), 4.0);
+        adaptive.observe(&"host", Outcome::Success);
+        assert_eq!(rate(), 6.0);
+        adaptive.observe(&"host", Outcome::Other);
+        assert_eq!(rate(), 6.0);
+        adaptive.observe(&"host", Outcome::Congestion);
+        assert_eq!(rate(), 3.0);
+        adaptive.observe(&"host", Outcome::Congestion);
+        assert_eq!(rate(), 2.0);
+        for _ in 0..10 {
+            adaptive.observe(&"host", Outcome::Success);
+        }
+        assert_eq!(rate(), 10.0);
+    }
+
+    #[test]
+    fn starts_at_new_quota() {
+        let adaptive = Adaptive::new(
+            Aimd::new(quota(1), quota(50)),
+            quota(10),
+            DefaultClock::default(),
+        )
+        .requota(quota(10).allow_burst(NonZeroU32::new(2).unwrap()));
+        let limiter = adaptive.limiter(&"host");
+        assert!(limiter.check_n(NonZeroU32::new(2).unwrap()).is_ok());
+        assert!(limiter.check().is_err());
+    }
+
+    #[test]
+    fn starts_within_bounds() {
+        let adaptive = Adaptive::new(
+            Aimd::new(quota(2), quota(10)),
+            quota(20),
+            DefaultClock::default(),
+        );
+        adaptive.limiter(&"host");
+        assert_eq!(adaptive.rate(&"host").map(f64::round), Some(10.0));
+    }
+
+    #[test]
+    fn never_tightens_on_success() {
+        let clock = FakeRelativeClock::default();
+        let adaptive = Adaptive::new(Aimd::new(quota(1), quota(50)), quota(4), clock.clone());
+        for _ in 0..4 {
+            assert!(adaptive.limiter(&"host").check().is_ok());
+            adaptive.observe(&"host", Outcome::Success);
+        }
+        assert!(adaptive.limiter(&"host").check().is_err());
+        clock.advance(Duration::from_millis(250));
+        assert!(adaptive.limiter(&"host").check().is_ok());
+    }
+
+    #[test]
+    fn empties_on_congestion() {
+        let clock = FakeRelativeClock::default();
+        let adaptive = Adaptive::new(Aimd::new(quota(1), quota(50)), quota(4), clock);
+        assert!(adaptive.limiter(&"host").check().is_ok());
+        adaptive.observe(&"host", Outcome::Congestion);
+        assert!(adaptive.limiter(&"host").check().is_err());
+    }
+}
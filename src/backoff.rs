@@ -0,0 +1,78 @@
+use crate::{RateLimitClock, RateLimitKey};
+use governor::{clock::Reference, nanos::Nanos};
+use http_types::{headers::RETRY_AFTER, other::RetryAfter, StatusCode};
+use std::{
+    collections::HashMap,
+    sync::{Mutex, PoisonError},
+    time::{Duration, SystemTime},
+};
+
+/// The longest servers can make requests wait, beyond any sensible backoff yet well
+/// within what a clock can represent.
+pub(crate) const MAX_DELAY: Duration = Duration::from_secs(60 * 60 * 24 * 365);
+
+/// The keys upstream servers asked to wait for, and until when.
+#[derive(Debug)]
+pub(crate) struct Backoff<K: RateLimitKey, C: RateLimitClock> {
+    until: Mutex<HashMap<K, C::Instant>>,
+}
+
+impl<K: RateLimitKey, C: RateLimitClock> Default for Backoff<K, C> {
+    fn default() -> Self {
+        Self {
+            until: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<K: RateLimitKey, C: RateLimitClock> Backoff<K, C> {
+    /// Returns how long requests for `key` have to wait, if at all.
+    pub(crate) fn wait_time(&self, key: &K, now: C::Instant) -> Option<Duration> {
+        let mut until = self.until.lock().unwrap_or_else(PoisonError::into_inner);
+        match until.get(key) {
+            Some(&blocked) if blocked > now => Some(blocked.duration_since(now).into()),
+            Some(_) => {
+                until.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Makes requests for `key` wait until `now + delay`, unless they already wait longer.
+    /// Delays are capped at [`MAX_DELAY`].
+    pub(crate) fn block(&self, key: K, now: C::Instant, delay: Duration) {
+        let blocked = now + Nanos::from(delay.min(MAX_DELAY));
+        let mut until = self.until.lock().unwrap_or_else(PoisonError::into_inner);
+        let entry = until.entry(key).or_insert(blocked);
+        *entry = (*entry).max(blocked);
+    }
+
+    /// Blocks `key` for as long as a 429 or 503 response asks for in its `Retry-After`
+    /// header, given either in seconds or as an HTTP date.
+    pub(crate) fn observe(&self, key: K, now: C::Instant, res: &surf::Response) {
+        if !matches!(
+            res.status(),
+            StatusCode::TooManyRequests | StatusCode::ServiceUnavailable
+        ) {
+            return;
+        }
+        if let Some(delay) = retry_after(res) {
+            self.block(key, now, delay);
+        }
+    }
+}
+
+/// Returns the delay the `Retry-After` header of `res` asks for, given either in seconds
+/// or as an HTTP date.
+fn retry_after(res: &surf::Response) -> Option<Duration> {
+    let value = res.header(RETRY_AFTER)?.last().as_str();
+    // Seconds are read here, as `RetryAfter` adds them to the current time unchecked.
+    if let Ok(seconds) = value.trim().parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+    RetryAfter::from_headers(res)
+        .ok()
+        .flatten()
+        .and_then(|retry_after| retry_after.duration_since(SystemTime::now()).ok())
+}
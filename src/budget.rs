@@ -0,0 +1,182 @@
+use crate::{backoff::MAX_DELAY, RateLimitClock, RateLimitKey};
+use governor::{clock::Reference, nanos::Nanos};
+use std::{
+    collections::HashMap,
+    sync::{Mutex, PoisonError},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Reset values above this many seconds are taken as Unix times rather than delays, as no
+/// server resets its window in over 30 years, and no Unix time is this low any more.
+const EPOCH_THRESHOLD: u64 = 1_000_000_000;
+
+/// How many requests a server still allows until its window resets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Remaining<I> {
+    count: u64,
+    reset: I,
+}
+
+/// The remaining budget servers reported for each key.
+#[derive(Debug)]
+pub(crate) struct Budgets<K: RateLimitKey, C: RateLimitClock> {
+    remaining: Mutex<HashMap<K, Remaining<C::Instant>>>,
+}
+
+impl<K: RateLimitKey, C: RateLimitClock> Default for Budgets<K, C> {
+    fn default() -> Self {
+        Self {
+            remaining: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<K: RateLimitKey, C: RateLimitClock> Budgets<K, C> {
+    /// Takes one request from the budget of `key`, or returns how long to wait if none
+    /// is left. Keys without a reported budget are always allowed.
+    pub(crate) fn acquire(&self, key: &K, now: C::Instant) -> Result<(), Duration> {
+        let mut remaining = self
+            .remaining
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        match remaining.get_mut(key) {
+            Some(budget) if budget.reset <= now => {
+                remaining.remove(key);
+                Ok(())
+            }
+            Some(Remaining { count: 0, reset }) => Err(reset.duration_since(now).into()),
+            Some(budget) => {
+                budget.count -= 1;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Gives back a request taken with [`Budgets::acquire`] that was not sent.
+    pub(crate) fn release(&self, key: &K) {
+        let mut remaining = self
+            .remaining
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(budget) = remaining.get_mut(key) {
+            budget.count += 1;
+        }
+    }
+
+    /// Replaces the budget of `key` with the one reported by the headers of `res`, if any.
+    pub(crate) fn observe(&self, key: K, now: C::Instant, res: &surf::Response) {
+        if let Some((count, reset)) = reported(res) {
+            let budget = Remaining {
+                count,
+                reset: now + Nanos::from(reset),
+            };
+            self.remaining
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .insert(key, budget);
+        }
+    }
+}
+
+/// Returns the remaining requests and the time until they reset reported by `res`, from
+/// the IETF `RateLimit` and `RateLimit-Policy` headers, or else from the
+/// `RateLimit-Remaining` and `RateLimit-Reset` or `X-RateLimit-Remaining` and
+/// `X-RateLimit-Reset` headers.
+fn reported(res: &surf::Response) -> Option<(u64, Duration)> {
+    let header = |name: &str| res.header(name).map(|values| values.last().as_str());
+    let number = |name: &str| header(name)?.trim().parse::<u64>().ok();
+    if let Some(value) = header("ratelimit") {
+        let window = header("ratelimit-policy").and_then(|policy| param(policy, "w"));
+        if let Some((count, reset)) = structured(value, window) {
+            return Some((count, reset_after(reset)));
+        }
+    }
+    ["ratelimit", "x-ratelimit"].iter().find_map(|prefix| {
+        let count = number(&format!("{prefix}-remaining"))?;
+        let reset = number(&format!("{prefix}-reset"))?;
+        Some((count, reset_after(reset)))
+    })
+}
+
+/// Parses a `RateLimit` header, either `"policy";r=50;t=30` with one item per policy,
+/// of which the one with the fewest remaining requests is used, or the older
+/// `limit=100, remaining=50, reset=30`. `window` is the reset of items without one.
+fn structured(value: &str, window: Option<u64>) -> Option<(u64, u64)> {
+    let items = value.split(',').filter_map(|item| {
+        let count = param(item, "r")?;
+        Some((count, param(item, "t").or(window)?))
+    });
+    items.min().or_else(|| {
+        let count = param(value, "remaining")?;
+        Some((count, param(value, "reset").or(window)?))
+    })
+}
+
+/// Returns the numeric parameter `name` of a header value such as `"default";q=100;w=60`
+/// or `limit=100, remaining=50`.
+fn param(value: &str, name: &str) -> Option<u64> {
+    value
+        .split([';', ','])
+        .filter_map(|param| param.split_once('='))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+        .and_then(|(_, number)| number.trim().parse().ok())
+}
+
+/// Converts a reset given either in seconds from now or as a Unix time to a delay, capped
+/// at [`MAX_DELAY`].
+fn reset_after(reset: u64) -> Duration {
+    let delay = if reset < EPOCH_THRESHOLD {
+        Duration::from_secs(reset)
+    } else {
+        match UNIX_EPOCH.checked_add(Duration::from_secs(reset)) {
+            Some(at) => at.duration_since(SystemTime::now()).unwrap_or_default(),
+            None => MAX_DELAY,
+        }
+    };
+    delay.min(MAX_DELAY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{param, reset_after, structured};
+    use crate::backoff::MAX_DELAY;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    #[test]
+    fn parses_parameters() {
+        assert_eq!(param("\"default\";q=100;w=60", "w"), Some(60));
+        assert_eq!(param("limit=100, remaining=50", "Remaining"), Some(50));
+        assert_eq!(param("limit=100", "remaining"), None);
+    }
+
+    #[test]
+    fn parses_structured_headers() {
+        assert_eq!(structured("\"default\";r=50;t=30", None), Some((50, 30)));
+        assert_eq!(
+            structured("\"second\";r=5;t=1, \"day\";r=2;t=600", None),
+            Some((2, 600))
+        );
+        assert_eq!(structured("\"default\";r=50", Some(60)), Some((50, 60)));
+        assert_eq!(structured("\"default\";r=50", None), None);
+        assert_eq!(
+            structured("limit=100, remaining=50, reset=30", None),
+            Some((50, 30))
+        );
+    }
+
+    #[test]
+    fn resets_after_delay_or_unix_time() {
+        assert_eq!(reset_after(30), Duration::from_secs(30));
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let reset = reset_after(now.as_secs() + 120);
+        assert!(reset > Duration::from_secs(110) && reset <= Duration::from_secs(120));
+        assert_eq!(reset_after(1_500_000_000), Duration::ZERO);
+    }
+
+    #[test]
+    fn caps_huge_resets() {
+        assert_eq!(reset_after(99_999_999_999), MAX_DELAY);
+        assert_eq!(reset_after(u64::MAX), MAX_DELAY);
+    }
+}
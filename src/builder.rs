@@ -0,0 +1,473 @@
+use crate::{
+    aimd::Adaptive, cost::Costs, default_marker, in_flight::InFlight, response::Handler, Aimd,
+    GovernorConfigError, GovernorMiddleware, Jitter, KeyExtractor, Layer, MissingKey, OnLimit,
+    OriginKey, RateLimitClock, RateLimitKey, RateLimited, RejectionResponse,
+};
+use governor::{clock::DefaultClock, Quota};
+use http_types::{
+    headers::{HeaderName, HeaderValue},
+    Method, Response,
+};
+use std::{num::NonZeroU32, sync::Arc, time::Duration};
+use surf::Request;
+
+const DAY: Duration = Duration::from_secs(60 * 60 * 24);
+
+#[derive(Debug, Clone, Copy)]
+enum Rate {
+    Quota(Quota),
+    Per { count: u32, period: Duration },
+}
+
+impl Rate {
+    fn quota(self) -> Result<Quota, GovernorConfigError> {
+        let (count, period) = match self {
+            Self::Quota(quota) => return Ok(quota),
+            Self::Per { count, period } => (count, period),
+        };
+        let count = NonZeroU32::new(count).ok_or(GovernorConfigError::ZeroCount)?;
+        if period.is_zero() {
+            return Err(GovernorConfigError::ZeroPeriod);
+        }
+        if period.as_nanos() > u128::from(u64::MAX) {
+            return Err(GovernorConfigError::Overflow { period });
+        }
+        Quota::with_period(period / count.get())
+            .map(|quota| quota.allow_burst(count))
+            .ok_or(GovernorConfigError::TooFrequent {
+                count: count.get(),
+                period,
+            })
+    }
+}
+
+/// A builder for [`GovernorMiddleware`], created with [`GovernorMiddleware::builder`].
+///
+/// Nothing is validated until [`GovernorBuilder::build`] is called, which reports
+/// any invalid configuration as a [`GovernorConfigError`].
+///
+/// # Example
+/// This constructs a client with a governor set to 10 requests per minute, at most 2 at once
+/// ```no_run
+/// use surf_governor::{GovernorMiddleware, OnLimit};
+/// use surf::{Client, Request, http::Method};
+/// use url::Url;
+///
+/// #[async_std::main]
+/// async fn main() -> surf::Result<()> {
+///     let req = Request::new(Method::Get, Url::parse("https://example.api")?);
+///     // Construct Surf client with a governor
+///     let governor = GovernorMiddleware::builder()
+///         .per_minute(10)
+///         .burst(2)
+///         .on_limit(OnLimit::Wait)
+///         .build()?;
+///     let client = Client::new().with(governor);
+///     let res = client.send(req).await?;
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct GovernorBuilder<K = String, C = DefaultClock> {
+    rate: Option<Rate>,
+    burst: Option<u32>,
+    windows: Vec<Quota>,
+    global: bool,
+    host_quotas: Vec<(String, Vec<Quota>)>,
+    host_groups: Vec<(String, Vec<String>, Quota)>,
+    layers: Vec<Layer>,
+    extractor: Arc<dyn KeyExtractor<Key = K>>,
+    on_missing_key: MissingKey<K>,
+    on_limit: OnLimit,
+    jitter: Jitter,
+    honor_retry_after: bool,
+    follow_rate_limit_headers: bool,
+    aimd: Option<Aimd>,
+    max_in_flight: Option<u32>,
+    costs: Costs,
+    marker: Option<(HeaderName, HeaderValue)>,
+    rate_limit_headers: bool,
+    rejection: RejectionResponse,
+    rejection_handler: Option<Handler<K>>,
+    clock: C,
+}
+
+impl Default for GovernorBuilder {
+    fn default() -> Self {
+        Self {
+            rate: None,
+            burst: None,
+            windows: Vec::new(),
+            global: false,
+            host_quotas: Vec::new(),
+            host_groups: Vec::new(),
+            layers: Vec::new(),
+            extractor: Arc::new(OriginKey),
+            on_missing_key: MissingKey::default(),
+            on_limit: OnLimit::default(),
+            jitter: Jitter::default(),
+            honor_retry_after: false,
+            follow_rate_limit_headers: false,
+            aimd: None,
+            max_in_flight: None,
+            costs: Costs::default(),
+            marker: default_marker(),
+            rate_limit_headers: false,
+            rejection: RejectionResponse::default(),
+            rejection_handler: None,
+            clock: DefaultClock::default(),
+        }
+    }
+}
+
+impl<K: RateLimitKey, C: RateLimitClock> GovernorBuilder<K, C> {
+    /// Uses the given [`Quota`] as is.
+    #[must_use]
+    pub fn quota(mut self, quota: Quota) -> Self {
+        self.rate = Some(Rate::Quota(quota));
+        self
+    }
+
+    /// Allows `count` requests every `period`.
+    #[must_use]
+    pub fn per(mut self, count: u32, period: Duration) -> Self {
+        self.rate = Some(Rate::Per { count, period });
+        self
+    }
+
+    /// Allows `count` requests every second.
+    #[must_use]
+    pub fn per_second(self, count: u32) -> Self {
+        self.per(count, Duration::from_secs(1))
+    }
+
+    /// Allows `count` requests every minute.
+    #[must_use]
+    pub fn per_minute(self, count: u32) -> Self {
+        self.per(count, Duration::from_secs(60))
+    }
+
+    /// Allows `count` requests every hour.
+    #[must_use]
+    pub fn per_hour(self, count: u32) -> Self {
+        self.per(count, Duration::from_secs(60 * 60))
+    }
+
+    /// Allows `count` requests every day.
+    #[must_use]
+    pub fn per_day(self, count: u32) -> Self {
+        self.per(count, DAY)
+    }
+
+    /// Sets the maximum number of requests that may be sent at once.
+    ///
+    /// Defaults to the burst size of the quota.
+    #[must_use]
+    pub fn burst(mut self, burst: u32) -> Self {
+        self.burst = Some(burst);
+        self
+    }
+
+    /// Additionally limits requests by `quota`.
+    ///
+    /// See [`GovernorMiddleware::with_window`] for details.
+    #[must_use]
+    pub fn window(mut self, quota: Quota) -> Self {
+        self.windows.push(quota);
+        self
+    }
+
+    /// Limits all requests with a single bucket regardless of their destination.
+    ///
+    /// See [`GovernorMiddleware::global`] for details.
+    #[must_use]
+    pub fn global(mut self) -> Self {
+        self.global = true;
+        self
+    }
+
+    /// Limits requests to `host` with `quota` instead of the default quota.
+    ///
+    /// See [`GovernorMiddleware::set_host_quota`] for the accepted forms of `host`.
+    #[must_use]
+    pub fn host_quota(self, host: impl Into<String>, quota: Quota) -> Self {
+        self.host_quotas(host, [quota])
+    }
+
+    /// Limits requests to `host` with every one of `quotas` instead of the default quotas.
+    ///
+    /// See [`GovernorMiddleware::set_host_quotas`] for details.
+    #[must_use]
+    pub fn host_quotas(
+        mut self,
+        host: impl Into<String>,
+        quotas: impl IntoIterator<Item = Quota>,
+    ) -> Self {
+        self.host_quotas
+            .push((host.into(), quotas.into_iter().collect()));
+        self
+    }
+
+    /// Makes requests to any of `hosts` draw from one shared bucket limited by `quota`.
+    ///
+    /// See [`GovernorMiddleware::set_host_group`] for details.
+    #[must_use]
+    pub fn host_group<H: Into<String>>(
+        mut self,
+        name: impl Into<String>,
+        hosts: impl IntoIterator<Item = H>,
+        quota: Quota,
+    ) -> Self {
+        let hosts = hosts.into_iter().map(Into::into).collect();
+        self.host_groups.push((name.into(), hosts, quota));
+        self
+    }
+
+    /// Enforces `layer` together with the host quotas and any other layers.
+    #[must_use]
+    pub fn layer(mut self, layer: Layer) -> Self {
+        self.layers.push(layer);
+        self
+    }
+
+    /// Sets the [`KeyExtractor`] deciding which bucket a request is counted against.
+    ///
+    /// Defaults to [`OriginKey`], use [`HostKey`](crate::HostKey) to share one bucket
+    /// between every port of a host. As the type of key may change, this resets any policy
+    /// set with [`GovernorBuilder::on_missing_key`] and drops any handler set with
+    /// [`GovernorBuilder::rejection_handler`].
+    #[must_use]
+    pub fn key_extractor<E: KeyExtractor>(self, extractor: E) -> GovernorBuilder<E::Key, C> {
+        GovernorBuilder {
+            rate: self.rate,
+            burst: self.burst,
+            windows: self.windows,
+            global: self.global,
+            host_quotas: self.host_quotas,
+            host_groups: self.host_groups,
+            layers: self.layers,
+            extractor: Arc::new(extractor),
+            on_missing_key: MissingKey::default(),
+            on_limit: self.on_limit,
+            jitter: self.jitter,
+            honor_retry_after: self.honor_retry_after,
+            follow_rate_limit_headers: self.follow_rate_limit_headers,
+            aimd: self.aimd,
+            max_in_flight: self.max_in_flight,
+            costs: self.costs,
+            marker: self.marker,
+            rate_limit_headers: self.rate_limit_headers,
+            rejection: self.rejection,
+            rejection_handler: None,
+            clock: self.clock,
+        }
+    }
+
+    /// Sets the [`Clock`](governor::clock::Clock) the middleware measures time with.
+    ///
+    /// Defaults to [`DefaultClock`], see [`GovernorMiddleware::with_clock`].
+    #[must_use]
+    pub fn clock<D: RateLimitClock>(self, clock: D) -> GovernorBuilder<K, D> {
+        GovernorBuilder {
+            rate: self.rate,
+            burst: self.burst,
+            windows: self.windows,
+            global: self.global,
+            host_quotas: self.host_quotas,
+            host_groups: self.host_groups,
+            layers: self.layers,
+            extractor: self.extractor,
+            on_missing_key: self.on_missing_key,
+            on_limit: self.on_limit,
+            jitter: self.jitter,
+            honor_retry_after: self.honor_retry_after,
+            follow_rate_limit_headers: self.follow_rate_limit_headers,
+            aimd: self.aimd,
+            max_in_flight: self.max_in_flight,
+            costs: self.costs,
+            marker: self.marker,
+            rate_limit_headers: self.rate_limit_headers,
+            rejection: self.rejection,
+            rejection_handler: self.rejection_handler,
+            clock,
+        }
+    }
+
+    /// Sets what happens to a request the key extractor produces no key for.
+    ///
+    /// Defaults to [`MissingKey::PassThrough`].
+    #[must_use]
+    pub fn on_missing_key(mut self, on_missing_key: MissingKey<K>) -> Self {
+        self.on_missing_key = on_missing_key;
+        self
+    }
+
+    /// Sets what happens to a request once the rate limit has been reached.
+    #[must_use]
+    pub fn on_limit(mut self, on_limit: OnLimit) -> Self {
+        self.on_limit = on_limit;
+        self
+    }
+
+    /// Sets the [`Jitter`] added to every delay the middleware imposes.
+    #[must_use]
+    pub fn jitter(mut self, jitter: Jitter) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Makes requests wait for as long as a 429 or 503 response asks for in its `Retry-After` header.
+    ///
+    /// See [`GovernorMiddleware::honor_retry_after`] for details.
+    #[must_use]
+    pub fn honor_retry_after(mut self) -> Self {
+        self.honor_retry_after = true;
+        self
+    }
+
+    /// Never sends more requests than a server reports it has left in its rate limit headers.
+    ///
+    /// See [`GovernorMiddleware::follow_rate_limit_headers`] for details.
+    #[must_use]
+    pub fn follow_rate_limit_headers(mut self) -> Self {
+        self.follow_rate_limit_headers = true;
+        self
+    }
+
+    /// Adapts the rate of each key to how its server responds.
+    ///
+    /// See [`GovernorMiddleware::with_aimd`] for details.
+    #[must_use]
+    pub fn aimd(mut self, aimd: Aimd) -> Self {
+        self.aimd = Some(aimd);
+        self
+    }
+
+    /// Limits the number of requests in flight for each key to `max`.
+    ///
+    /// See [`GovernorMiddleware::with_max_in_flight`] for details.
+    #[must_use]
+    pub fn max_in_flight(mut self, max: u32) -> Self {
+        self.max_in_flight = Some(max);
+        self
+    }
+
+    /// Makes requests cost the number of cells returned by `cost` instead of one.
+    ///
+    /// See [`GovernorMiddleware::with_cost`] for details.
+    #[must_use]
+    pub fn cost<F>(mut self, cost: F) -> Self
+    where
+        F: Fn(&Request) -> u32 + Send + Sync + 'static,
+    {
+        self.costs.set_fn(Arc::new(cost));
+        self
+    }
+
+    /// Makes requests with `method` whose path starts with the segments of `path` cost
+    /// `cost` cells instead of one.
+    ///
+    /// See [`GovernorMiddleware::with_route_cost`] for details.
+    #[must_use]
+    pub fn route_cost(mut self, method: Method, path: impl Into<String>, cost: u32) -> Self {
+        self.costs.set_route(method, path.into(), cost);
+        self
+    }
+
+    /// Sets the header marking the rejection responses of the middleware.
+    ///
+    /// See [`GovernorMiddleware::with_marker_header`] for details.
+    #[must_use]
+    pub fn marker_header(mut self, name: impl Into<HeaderName>, value: HeaderValue) -> Self {
+        self.marker = Some((name.into(), value));
+        self
+    }
+
+    /// Leaves the rejection responses of the middleware without a marker header.
+    #[must_use]
+    pub fn no_marker_header(mut self) -> Self {
+        self.marker = None;
+        self
+    }
+
+    /// Adds precise rate limit information to the rejection responses of the middleware.
+    ///
+    /// See [`GovernorMiddleware::with_rate_limit_headers`] for details.
+    #[must_use]
+    pub fn rate_limit_headers(mut self) -> Self {
+        self.rate_limit_headers = true;
+        self
+    }
+
+    /// Sets the responses the middleware rejects requests with.
+    ///
+    /// See [`GovernorMiddleware::with_rejection_response`] for details.
+    #[must_use]
+    pub fn rejection_response(mut self, rejection: RejectionResponse) -> Self {
+        self.rejection = rejection;
+        self
+    }
+
+    /// Builds the responses the middleware rejects requests with by calling `handler`.
+    ///
+    /// See [`GovernorMiddleware::with_rejection_handler`] for details.
+    #[must_use]
+    pub fn rejection_handler<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(&Request, &RateLimited<K>) -> Response + Send + Sync + 'static,
+    {
+        self.rejection_handler = Some(Handler::new(handler));
+        self
+    }
+
+    /// Builds the middleware, returning a [`GovernorConfigError`] if the configuration is invalid.
+    pub fn build(self) -> Result<GovernorMiddleware<K, C>, GovernorConfigError> {
+        let mut quota = self
+            .rate
+            .ok_or(GovernorConfigError::MissingQuota)?
+            .quota()?;
+        if let Some(burst) = self.burst {
+            quota =
+                quota.allow_burst(NonZeroU32::new(burst).ok_or(GovernorConfigError::ZeroBurst)?);
+        }
+        let mut governor = GovernorMiddleware::new(quota, self.extractor, self.clock.clone())
+            .on_missing_key(self.on_missing_key)
+            .on_limit(self.on_limit)
+            .with_jitter(self.jitter);
+        if self.global {
+            governor = governor.global();
+        }
+        if self.honor_retry_after {
+            governor = governor.honor_retry_after();
+        }
+        if self.follow_rate_limit_headers {
+            governor = governor.follow_rate_limit_headers();
+        }
+        for quota in self.windows {
+            governor = governor.with_window(quota);
+        }
+        for (host, quotas) in self.host_quotas {
+            governor.set_host_quotas(&host, quotas);
+        }
+        for (name, hosts, quota) in &self.host_groups {
+            governor.set_host_group(name, hosts, *quota);
+        }
+        for layer in self.layers {
+            governor.add_layer(layer);
+        }
+        governor.costs = Arc::new(self.costs);
+        governor.marker = self.marker;
+        governor.rate_limit_headers = self.rate_limit_headers;
+        governor.rejection = self.rejection;
+        governor.rejection_handler = self.rejection_handler;
+        if let Some(max) = self.max_in_flight {
+            let max = NonZeroU32::new(max).ok_or(GovernorConfigError::ZeroMaxInFlight)?;
+            governor.in_flight = Some(Arc::new(InFlight::new(max)));
+        }
+        if let Some(aimd) = self.aimd {
+            aimd.validate()?;
+            governor.aimd = Some(Arc::new(Adaptive::new(aimd, quota, self.clock)));
+        }
+        Ok(governor)
+    }
+}
@@ -0,0 +1,9 @@
+use governor::clock::Clock;
+use std::fmt::Debug;
+
+/// Implemented for every [`Clock`] the middleware can measure time with, such as the
+/// default [`DefaultClock`](governor::clock::DefaultClock) or a
+/// [`FakeRelativeClock`](governor::clock::FakeRelativeClock) advanced by hand in tests.
+pub trait RateLimitClock: Clock + Debug + Send + Sync + 'static {}
+
+impl<T> RateLimitClock for T where T: Clock + Debug + Send + Sync + 'static {}
@@ -0,0 +1,93 @@
+use crate::layer;
+use http_types::Method;
+use std::{fmt, sync::Arc};
+use surf::Request;
+
+/// The number of cells a request consumes from the limiters, attached to a request as an
+/// extension to override any cost configured on the middleware.
+///
+/// A request costing more than the burst size of a limiter can never be allowed, and
+/// fails with an [`ExceedsCapacityError`](crate::ExceedsCapacityError). A request
+/// costing nothing is not counted by the limiters at all, nor against a budget reported
+/// by the server, which counts any other request once whatever its cost.
+///
+/// # Example
+/// This sends a bulk request counting as 10 requests
+/// ```no_run
+/// use surf_governor::{Cost, GovernorMiddleware};
+/// use surf::{Client, Request, http::Method};
+/// use url::Url;
+///
+/// #[async_std::main]
+/// async fn main() -> surf::Result<()> {
+///     let mut req = Request::new(Method::Post, Url::parse("https://example.api/bulk")?);
+///     req.set_ext(Cost::new(10));
+///     // Construct Surf client with a governor
+///     let client = Client::new().with(GovernorMiddleware::per_second(30)?);
+///     let res = client.send(req).await?;
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Cost(u32);
+
+impl Cost {
+    /// Constructs a cost of `cells` cells.
+    #[must_use]
+    pub fn new(cells: u32) -> Self {
+        Self(cells)
+    }
+
+    /// The number of cells.
+    #[must_use]
+    pub fn get(self) -> u32 {
+        self.0
+    }
+}
+
+type CostFn = dyn Fn(&Request) -> u32 + Send + Sync;
+
+/// The costs of requests: per route, or else from a function, or else one.
+#[derive(Clone, Default)]
+pub(crate) struct Costs {
+    routes: Vec<(Method, String, u32)>,
+    by: Option<Arc<CostFn>>,
+}
+
+impl Costs {
+    pub(crate) fn set_route(&mut self, method: Method, path: String, cost: u32) {
+        self.routes
+            .retain(|(m, p, _)| !(*m == method && *p == path));
+        self.routes.push((method, path, cost));
+    }
+
+    pub(crate) fn set_fn(&mut self, by: Arc<CostFn>) {
+        self.by = Some(by);
+    }
+
+    /// Returns the cost of `req`, from its [`Cost`] extension, or the first route it
+    /// matches, or the cost function.
+    pub(crate) fn of(&self, req: &Request) -> u32 {
+        if let Some(cost) = req.ext::<Cost>() {
+            return cost.get();
+        }
+        let route = self
+            .routes
+            .iter()
+            .find(|(method, path, _)| layer::matches(Some(method), Some(path), req));
+        match (route, &self.by) {
+            (Some(&(.., cost)), _) => cost,
+            (None, Some(by)) => by(req),
+            (None, None) => 1,
+        }
+    }
+}
+
+impl fmt::Debug for Costs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Costs")
+            .field("routes", &self.routes)
+            .field("by", &self.by.as_ref().map(|_| "Fn(&Request) -> u32"))
+            .finish()
+    }
+}
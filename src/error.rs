@@ -0,0 +1,208 @@
+use governor::Quota;
+use std::{fmt, time::Duration};
+use surf::Url;
+
+/// An error describing why a [`GovernorBuilder`](crate::GovernorBuilder) could not build a middleware.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum GovernorConfigError {
+    /// No quota was given to the builder.
+    MissingQuota,
+    /// The number of requests allowed per period was zero.
+    ZeroCount,
+    /// The period was zero.
+    ZeroPeriod,
+    /// The burst size was zero.
+    ZeroBurst,
+    /// The period is too long to be represented by the limiter.
+    Overflow {
+        /// The period that was given.
+        period: Duration,
+    },
+    /// More than one request per nanosecond was requested, which the limiter can't represent.
+    TooFrequent {
+        /// The number of requests that was given.
+        count: u32,
+        /// The period that was given.
+        period: Duration,
+    },
+    /// The maximum number of requests in flight was zero.
+    ZeroMaxInFlight,
+    /// The floor of an [`Aimd`](crate::Aimd) controller is above its ceiling.
+    FloorAboveCeiling,
+    /// The decrease of an [`Aimd`](crate::Aimd) controller is not between 0 and 1.
+    InvalidDecrease,
+}
+
+impl fmt::Display for GovernorConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingQuota => write!(f, "no quota was configured"),
+            Self::ZeroCount => write!(f, "the number of requests per period must not be zero"),
+            Self::ZeroPeriod => write!(f, "the period must not be zero"),
+            Self::ZeroBurst => write!(f, "the burst size must not be zero"),
+            Self::Overflow { period } => {
+                write!(f, "the period of {period:?} is too long to be represented")
+            }
+            Self::TooFrequent { count, period } => write!(
+                f,
+                "{count} requests per {period:?} is more than one request per nanosecond"
+            ),
+            Self::ZeroMaxInFlight => {
+                write!(
+                    f,
+                    "the maximum number of requests in flight must not be zero"
+                )
+            }
+            Self::FloorAboveCeiling => write!(f, "the floor rate must not be above the ceiling"),
+            Self::InvalidDecrease => write!(f, "the decrease factor must be between 0 and 1"),
+        }
+    }
+}
+
+impl std::error::Error for GovernorConfigError {}
+
+/// The error returned for a request without a rate-limiting key when the middleware
+/// is configured with [`MissingKey::Error`](crate::MissingKey::Error).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MissingKeyError {
+    url: Url,
+}
+
+impl MissingKeyError {
+    pub(crate) fn new(url: Url) -> Self {
+        Self { url }
+    }
+
+    /// The URL of the request no key could be derived from.
+    #[must_use]
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+}
+
+impl fmt::Display for MissingKeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "no rate-limiting key could be derived for {}", self.url)
+    }
+}
+
+impl std::error::Error for MissingKeyError {}
+
+/// The error returned for a request whose [`Cost`](crate::Cost) exceeds the burst size of
+/// one of its limiters, so that it could never be allowed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExceedsCapacityError {
+    cost: u32,
+    capacity: u32,
+}
+
+impl ExceedsCapacityError {
+    pub(crate) fn new(cost: u32, capacity: u32) -> Self {
+        Self { cost, capacity }
+    }
+
+    /// The number of cells the request costs.
+    #[must_use]
+    pub fn cost(&self) -> u32 {
+        self.cost
+    }
+
+    /// The burst size of the limiter the request exceeds.
+    #[must_use]
+    pub fn capacity(&self) -> u32 {
+        self.capacity
+    }
+}
+
+impl fmt::Display for ExceedsCapacityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "a request costing {} cells exceeds the burst size of {}",
+            self.cost, self.capacity
+        )
+    }
+}
+
+impl std::error::Error for ExceedsCapacityError {}
+
+/// Why the middleware itself rejected a request, telling it apart from a 429 response of
+/// the server.
+///
+/// It is returned as the error of a rejected request when the middleware is configured
+/// with [`OnLimit::Error`](crate::OnLimit::Error), and attached as an extension to its
+/// 429 responses otherwise.
+///
+/// # Example
+/// This retries a request rejected by the governor once it would be allowed
+/// ```no_run
+/// use surf_governor::{GovernorMiddleware, OnLimit, RateLimited};
+/// use surf::{Client, Request, http::Method};
+/// use url::Url;
+///
+/// #[async_std::main]
+/// async fn main() -> surf::Result<()> {
+///     let req = Request::new(Method::Get, Url::parse("https://example.api")?);
+///     // Construct Surf client with a governor
+///     let client = Client::new().with(GovernorMiddleware::per_second(30)?.on_limit(OnLimit::Error));
+///     let res = match client.send(req.clone()).await {
+///         Err(err) => match err.downcast_ref::<RateLimited>() {
+///             Some(limited) => {
+///                 async_std::task::sleep(limited.wait()).await;
+///                 client.send(req).await?
+///             }
+///             None => return Err(err),
+///         },
+///         res => res?,
+///     };
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RateLimited<K = String> {
+    key: Option<K>,
+    wait: Duration,
+    quota: Option<Quota>,
+}
+
+impl<K> RateLimited<K> {
+    pub(crate) fn new(key: Option<K>, wait: Duration, quota: Option<Quota>) -> Self {
+        Self { key, wait, quota }
+    }
+
+    /// The key of the request, if it has one.
+    #[must_use]
+    pub fn key(&self) -> Option<&K> {
+        self.key.as_ref()
+    }
+
+    /// How long to wait before the request would be allowed.
+    ///
+    /// For a request rejected because too many requests with its key are in flight, see
+    /// [`GovernorMiddleware::with_max_in_flight`](crate::GovernorMiddleware::with_max_in_flight),
+    /// this is a minimum of 100ms rather than the time until one of them completes.
+    #[must_use]
+    pub fn wait(&self) -> Duration {
+        self.wait
+    }
+
+    /// The quota that rejected the request, if the request was not rejected by an upstream
+    /// backoff, budget or limit of requests in flight.
+    #[must_use]
+    pub fn quota(&self) -> Option<Quota> {
+        self.quota
+    }
+}
+
+impl<K: fmt::Debug> fmt::Display for RateLimited<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.key {
+            Some(key) => write!(f, "requests for {key:?} are rate limited")?,
+            None => write!(f, "requests are rate limited")?,
+        }
+        write!(f, ", retry after {:?}", self.wait)
+    }
+}
+
+impl<K: fmt::Debug> std::error::Error for RateLimited<K> {}
@@ -0,0 +1,52 @@
+use crate::RateLimitKey;
+use async_lock::{Semaphore, SemaphoreGuardArc};
+use std::{
+    collections::HashMap,
+    num::NonZeroU32,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+/// How long a request rejected because too many are in flight is told to wait, as there
+/// is no telling when another one completes.
+pub(crate) const IN_FLIGHT_WAIT: Duration = Duration::from_millis(100);
+
+/// The slots for requests in flight of each key.
+#[derive(Debug)]
+pub(crate) struct InFlight<K: RateLimitKey> {
+    max: NonZeroU32,
+    slots: Mutex<HashMap<K, Arc<Semaphore>>>,
+}
+
+impl<K: RateLimitKey> InFlight<K> {
+    pub(crate) fn new(max: NonZeroU32) -> Self {
+        Self {
+            max,
+            slots: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the same limit without any requests in flight, for another type of key.
+    pub(crate) fn rekey<L: RateLimitKey>(&self) -> InFlight<L> {
+        InFlight::new(self.max)
+    }
+
+    /// Takes a slot for a request with `key`, or returns `None` if all are taken.
+    pub(crate) fn try_acquire(&self, key: &K) -> Option<SemaphoreGuardArc> {
+        self.slots(key).try_acquire_arc()
+    }
+
+    /// Waits for a slot for a request with `key`.
+    pub(crate) async fn acquire(&self, key: &K) -> SemaphoreGuardArc {
+        self.slots(key).acquire_arc().await
+    }
+
+    fn slots(&self, key: &K) -> Arc<Semaphore> {
+        let mut slots = self.slots.lock().unwrap_or_else(PoisonError::into_inner);
+        let max = self.max.get() as usize;
+        slots
+            .entry(key.clone())
+            .or_insert_with(|| Arc::new(Semaphore::new(max)))
+            .clone()
+    }
+}
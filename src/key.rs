@@ -0,0 +1,219 @@
+use http_types::{headers::HeaderName, url::Origin, Method};
+use std::{fmt::Debug, hash::Hash};
+use surf::Request;
+
+/// Implemented for every type that can be used as a rate-limiting key.
+pub trait RateLimitKey: Clone + Hash + Eq + Debug + Send + Sync + 'static {}
+
+impl<T> RateLimitKey for T where T: Clone + Hash + Eq + Debug + Send + Sync + 'static {}
+
+/// Decides which bucket of the limiter a request is counted against.
+///
+/// Requests that produce the same key share a quota, requests with different keys are
+/// limited independently of each other.
+///
+/// # Example
+/// This limits requests per value of the `Authorization` header
+/// ```no_run
+/// use surf_governor::{GovernorMiddleware, HeaderKey};
+/// use surf::{Client, http::headers::AUTHORIZATION};
+///
+/// # fn main() -> Result<(), surf_governor::GovernorConfigError> {
+/// let governor = GovernorMiddleware::builder()
+///     .per_second(10)
+///     .key_extractor(HeaderKey::new(AUTHORIZATION))
+///     .build()?;
+/// let client = Client::new().with(governor);
+/// # Ok(())
+/// # }
+/// ```
+pub trait KeyExtractor: Debug + Send + Sync + 'static {
+    /// The type of key produced by this extractor.
+    type Key: RateLimitKey;
+
+    /// Returns the key for `req`, or `None` if no key can be derived from it.
+    fn extract(&self, req: &Request) -> Option<Self::Key>;
+}
+
+/// What the middleware does with a request its [`KeyExtractor`] produces no key for,
+/// e.g. a `file:` or `data:` URL without a host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MissingKey<K> {
+    /// Send the request without counting it against any bucket that needs a key.
+    PassThrough,
+    /// Limit the request under the given shared key.
+    Fallback(K),
+    /// Fail the request with a [`MissingKeyError`](crate::MissingKeyError).
+    Error,
+}
+
+impl<K> Default for MissingKey<K> {
+    fn default() -> Self {
+        Self::PassThrough
+    }
+}
+
+/// Keys requests by the host of their URL, e.g. `example.com`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HostKey;
+
+impl KeyExtractor for HostKey {
+    type Key = String;
+
+    fn extract(&self, req: &Request) -> Option<Self::Key> {
+        req.url().host_str().map(ToOwned::to_owned)
+    }
+}
+
+/// Keys requests by the host and effective port of their URL, e.g. `example.com:443`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HostPortKey;
+
+impl KeyExtractor for HostPortKey {
+    type Key = String;
+
+    fn extract(&self, req: &Request) -> Option<Self::Key> {
+        let url = req.url();
+        let host = url.host_str()?;
+        Some(match url.port_or_known_default() {
+            Some(port) => format!("{host}:{port}"),
+            None => host.to_owned(),
+        })
+    }
+}
+
+/// Keys requests by the origin of their URL, e.g. `https://example.com:8080`.
+///
+/// The port is omitted when it is the default for the scheme.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OriginKey;
+
+impl KeyExtractor for OriginKey {
+    type Key = String;
+
+    fn extract(&self, req: &Request) -> Option<Self::Key> {
+        match req.url().origin() {
+            origin @ Origin::Tuple(..) => Some(origin.ascii_serialization()),
+            Origin::Opaque(_) => None,
+        }
+    }
+}
+
+/// Keys requests by the origin and the first path segments of their URL,
+/// e.g. `https://example.com/v1/orders` for two segments.
+///
+/// Requests with fewer path segments are keyed by all the segments they have.
+#[derive(Debug, Clone, Copy)]
+pub struct PathPrefixKey {
+    segments: usize,
+}
+
+impl PathPrefixKey {
+    /// Constructs an extractor that keys requests by their first `segments` path segments.
+    #[must_use]
+    pub const fn new(segments: usize) -> Self {
+        Self { segments }
+    }
+}
+
+impl KeyExtractor for PathPrefixKey {
+    type Key = String;
+
+    fn extract(&self, req: &Request) -> Option<Self::Key> {
+        let mut key = OriginKey.extract(req)?;
+        if let Some(segments) = req.url().path_segments() {
+            for segment in segments.filter(|s| !s.is_empty()).take(self.segments) {
+                key.push('/');
+                key.push_str(segment);
+            }
+        }
+        Some(key)
+    }
+}
+
+/// Keys requests by the value of a request header, e.g. an API token.
+///
+/// Requests without the header produce no key.
+#[derive(Debug, Clone)]
+pub struct HeaderKey {
+    name: HeaderName,
+}
+
+impl HeaderKey {
+    /// Constructs an extractor that keys requests by the value of the header `name`.
+    #[must_use]
+    pub fn new(name: impl Into<HeaderName>) -> Self {
+        Self { name: name.into() }
+    }
+}
+
+impl KeyExtractor for HeaderKey {
+    type Key = String;
+
+    fn extract(&self, req: &Request) -> Option<Self::Key> {
+        req.header(&self.name)
+            .map(|values| values.last().as_str().to_owned())
+    }
+}
+
+/// Keys requests by their HTTP method.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MethodKey;
+
+impl KeyExtractor for MethodKey {
+    type Key = Method;
+
+    fn extract(&self, req: &Request) -> Option<Self::Key> {
+        Some(req.method())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use surf::Url;
+
+    fn request(method: Method, url: &str) -> Request {
+        Request::new(method, Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn extracts_keys() {
+        let req = request(Method::Post, "http://example.com:8080/v1/orders/42");
+        assert_eq!(HostKey.extract(&req).unwrap(), "example.com");
+        assert_eq!(HostPortKey.extract(&req).unwrap(), "example.com:8080");
+        assert_eq!(OriginKey.extract(&req).unwrap(), "http://example.com:8080");
+        assert_eq!(
+            PathPrefixKey::new(2).extract(&req).unwrap(),
+            "http://example.com:8080/v1/orders"
+        );
+        assert_eq!(MethodKey.extract(&req).unwrap(), Method::Post);
+        let default_port = request(Method::Get, "https://example.com/");
+        assert_eq!(
+            HostPortKey.extract(&default_port).unwrap(),
+            "example.com:443"
+        );
+        assert_eq!(
+            OriginKey.extract(&default_port).unwrap(),
+            "https://example.com"
+        );
+    }
+
+    #[test]
+    fn extracts_header_keys() {
+        let mut req = request(Method::Get, "https://example.com/");
+        let extractor = HeaderKey::new("X-Api-Key");
+        assert_eq!(extractor.extract(&req), None);
+        req.insert_header("X-Api-Key", "secret");
+        assert_eq!(extractor.extract(&req).unwrap(), "secret");
+    }
+
+    #[test]
+    fn hostless_urls_have_no_key() {
+        let req = request(Method::Get, "data:text/plain,hello");
+        assert_eq!(HostKey.extract(&req), None);
+        assert_eq!(HostPortKey.extract(&req), None);
+        assert_eq!(OriginKey.extract(&req), None);
+        assert_eq!(PathPrefixKey::new(1).extract(&req), None);
+    }
+}
@@ -0,0 +1,111 @@
+use governor::Quota;
+use http_types::Method;
+use surf::Request;
+
+/// An additional limit enforced together with the host quotas of a middleware.
+///
+/// A request is only sent once the quota of its host and the quotas of every layer
+/// applying to it have capacity; none of them is consumed when another one rejects it.
+///
+/// # Example
+/// This limits a client to 100 requests per second overall, 20 per second per host and
+/// 2 per second for `POST /v1/orders`
+/// ```no_run
+/// use surf_governor::{GovernorMiddleware, Layer};
+/// use surf::{Client, http::Method};
+///
+/// use governor::Quota;
+/// use std::num::NonZeroU32;
+///
+/// # fn main() -> Result<(), surf_governor::GovernorConfigError> {
+/// let quota = |count| Quota::per_second(NonZeroU32::new(count).unwrap());
+/// let governor = GovernorMiddleware::builder()
+///     .per_second(20)
+///     .layer(Layer::global(quota(100)))
+///     .layer(Layer::route(Method::Post, "/v1/orders", quota(2)))
+///     .build()?;
+/// let client = Client::new().with(governor);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Layer {
+    quota: Quota,
+    method: Option<Method>,
+    path: Option<String>,
+    per_key: bool,
+}
+
+impl Layer {
+    /// Constructs a layer limiting all requests together.
+    #[must_use]
+    pub fn global(quota: Quota) -> Self {
+        Self {
+            quota,
+            method: None,
+            path: None,
+            per_key: false,
+        }
+    }
+
+    /// Constructs a layer limiting requests whose path starts with the segments of `path`,
+    /// whatever their method.
+    #[must_use]
+    pub fn path(path: impl Into<String>, quota: Quota) -> Self {
+        Self {
+            path: Some(path.into()),
+            ..Self::global(quota)
+        }
+    }
+
+    /// Constructs a layer limiting requests with `method` whose path starts with the
+    /// segments of `path`.
+    #[must_use]
+    pub fn route(method: Method, path: impl Into<String>, quota: Quota) -> Self {
+        Self {
+            method: Some(method),
+            ..Self::path(path, quota)
+        }
+    }
+
+    /// Limits the requests of this layer per key instead of all together.
+    #[must_use]
+    pub fn per_key(mut self) -> Self {
+        self.per_key = true;
+        self
+    }
+
+    pub(crate) fn quota(&self) -> Quota {
+        self.quota
+    }
+
+    pub(crate) fn is_per_key(&self) -> bool {
+        self.per_key
+    }
+
+    pub(crate) fn applies_to(&self, req: &Request) -> bool {
+        matches(self.method.as_ref(), self.path.as_deref(), req)
+    }
+
+    /// Returns whether this layer applies to every request, whatever its method and path.
+    pub(crate) fn applies_to_all(&self) -> bool {
+        self.method.is_none() && self.path.is_none()
+    }
+}
+
+/// Returns whether `req` has `method`, if any, and a path starting with the segments of
+/// `path`, if any.
+pub(crate) fn matches(method: Option<&Method>, path: Option<&str>, req: &Request) -> bool {
+    if matches!(method, Some(method) if *method != req.method()) {
+        return false;
+    }
+    let path = match path {
+        Some(path) => path,
+        None => return true,
+    };
+    let mut prefix = path.split('/').filter(|s| !s.is_empty());
+    match req.url().path_segments() {
+        Some(mut segments) => prefix.all(|expected| segments.next() == Some(expected)),
+        None => prefix.next().is_none(),
+    }
+}
@@ -31,31 +31,153 @@
 //! [surf]: https://github.com/http-rs/surf
 //! [governor]: https://github.com/antifuchs/governor
 
-// TODO: figure out how to add jitter support using `governor::Jitter`.
 // TODO: add more unit tests.
-use governor::{
-    clock::{Clock, DefaultClock},
-    state::keyed::DefaultKeyedStateStore,
-    Quota, RateLimiter,
+mod aimd;
+mod backoff;
+mod budget;
+mod builder;
+mod clock;
+mod cost;
+mod error;
+mod in_flight;
+mod key;
+mod layer;
+mod pattern;
+mod registry;
+mod response;
+mod rules;
+mod state;
+
+pub use aimd::Aimd;
+pub use builder::GovernorBuilder;
+pub use clock::RateLimitClock;
+pub use cost::Cost;
+pub use error::{ExceedsCapacityError, GovernorConfigError, MissingKeyError, RateLimited};
+pub use key::{
+    HeaderKey, HostKey, HostPortKey, KeyExtractor, MethodKey, MissingKey, OriginKey, PathPrefixKey,
+    RateLimitKey,
+};
+pub use layer::Layer;
+pub use registry::GovernorRegistry;
+pub use response::{RejectionBody, RejectionResponse};
+
+use aimd::{Adaptive, Outcome};
+use backoff::Backoff;
+use budget::Budgets;
+use cost::Costs;
+use futures_timer::Delay;
+use governor::{clock::DefaultClock, Quota};
+use http_types::{
+    headers::{self, HeaderName, HeaderValue},
+    Method, Response, StatusCode,
+};
+use in_flight::{InFlight, IN_FLIGHT_WAIT};
+use rand::Rng;
+use response::Handler;
+use rules::{Limiter, Rejection, Rules, Target};
+use std::{
+    convert::TryInto,
+    error::Error,
+    num::NonZeroU32,
+    sync::{Arc, Mutex, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    time::Duration,
 };
-use http_types::{headers, Response, StatusCode};
-use lazy_static::lazy_static;
-use std::{convert::TryInto, error::Error, num::NonZeroU32, sync::Arc, time::Duration};
-use surf::{middleware::Next, Client, Request, Result};
+use surf::{middleware::Next, Client, Request, Result, Url};
+
+/// What the middleware does with a request once the rate limit has been reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnLimit {
+    /// Respond with status code 429 (too many requests) and a `Retry-After` header
+    /// with the amount of time that needs to pass before another request will be allowed.
+    Respond,
+    /// Wait until the limiter allows the request, then send it.
+    Wait,
+    /// Return a [`RateLimited`] error with the key of the request, how long it needs to wait
+    /// and the quota that rejected it.
+    Error,
+}
+
+impl Default for OnLimit {
+    fn default() -> Self {
+        Self::Respond
+    }
+}
+
+/// A random amount of time added to every delay the middleware imposes.
+///
+/// The jitter is applied both when waiting for the limiter and to the `Retry-After`
+/// header of the 429 response, so that clients sharing one upstream don't all retry
+/// at the same instant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Jitter {
+    min: Duration,
+    interval: Duration,
+}
+
+impl Jitter {
+    /// Constructs a jitter of at least `min` plus a random duration of up to `interval`.
+    #[must_use]
+    pub const fn new(min: Duration, interval: Duration) -> Self {
+        Self { min, interval }
+    }
+
+    /// Constructs a jitter of a random duration of up to `max`.
+    #[must_use]
+    pub const fn up_to(max: Duration) -> Self {
+        Self::new(Duration::ZERO, max)
+    }
+
+    fn get(&self) -> Duration {
+        self.min + rand::thread_rng().gen_range(Duration::ZERO..=self.interval)
+    }
+}
 
-lazy_static! {
-    static ref CLOCK: DefaultClock = DefaultClock::default();
+impl From<Jitter> for governor::Jitter {
+    fn from(jitter: Jitter) -> Self {
+        Self::new(jitter.min, jitter.interval)
+    }
 }
 
-/// Once the rate limit has been reached, the middleware will respond with
+/// Once the rate limit has been reached, the middleware will by default respond with
 /// status code 429 (too many requests) and a `Retry-After` header with the amount
 /// of time that needs to pass before another request will be allowed.
+///
+/// See [`GovernorMiddleware::on_limit`] to delay requests instead.
+///
+/// Requests are limited per origin (scheme, host and port) by default,
+/// see [`KeyExtractor`] for other strategies.
+///
+/// Clones of the middleware share their limiters, so a clone kept aside can be used
+/// to change quotas with [`GovernorMiddleware::set_host_quota`] after it has been
+/// added to a client, and clones added to several clients limit them together. See
+/// [`GovernorRegistry`] to share governors by name.
 #[derive(Debug, Clone)]
-pub struct GovernorMiddleware {
-    limiter: Arc<RateLimiter<String, DefaultKeyedStateStore<String>, DefaultClock>>,
+pub struct GovernorMiddleware<K: RateLimitKey = String, C: RateLimitClock = DefaultClock> {
+    rules: Arc<RwLock<Rules<K, C>>>,
+    checks: Arc<Mutex<()>>,
+    extractor: Arc<dyn KeyExtractor<Key = K>>,
+    on_missing_key: MissingKey<K>,
+    on_limit: OnLimit,
+    jitter: Jitter,
+    backoff: Option<Arc<Backoff<K, C>>>,
+    budgets: Option<Arc<Budgets<K, C>>>,
+    aimd: Option<Arc<Adaptive<K, C>>>,
+    in_flight: Option<Arc<InFlight<K>>>,
+    costs: Arc<Costs>,
+    marker: Option<(HeaderName, HeaderValue)>,
+    rate_limit_headers: bool,
+    rejection: RejectionResponse,
+    rejection_handler: Option<Handler<K>>,
+    clock: C,
 }
 
 impl GovernorMiddleware {
+    /// Returns a [`GovernorBuilder`] for configuring every aspect of the middleware.
+    #[must_use]
+    pub fn builder() -> GovernorBuilder {
+        GovernorBuilder::default()
+    }
+
     /// Constructs a rate-limiting middleware from a [`Duration`] that allows one request in the given time interval.
     ///
     /// If the time interval is zero, returns `None`.
@@ -79,11 +201,11 @@ impl GovernorMiddleware {
     /// ```
     #[must_use]
     pub fn with_period(duration: Duration) -> Option<Self> {
-        Some(Self {
-            limiter: Arc::new(RateLimiter::<String, _, _>::keyed(Quota::with_period(
-                duration,
-            )?)),
-        })
+        Some(Self::new(
+            Quota::with_period(duration)?,
+            Arc::new(OriginKey),
+            DefaultClock::default(),
+        ))
     }
 
     /// Constructs a rate-limiting middleware that allows a specified number of requests every second.
@@ -111,11 +233,11 @@ impl GovernorMiddleware {
         T: TryInto<NonZeroU32>,
         T::Error: Error + Send + Sync + 'static,
     {
-        Ok(Self {
-            limiter: Arc::new(RateLimiter::<String, _, _>::keyed(Quota::per_second(
-                times.try_into()?,
-            ))),
-        })
+        Ok(Self::new(
+            Quota::per_second(times.try_into()?),
+            Arc::new(OriginKey),
+            DefaultClock::default(),
+        ))
     }
 
     /// Constructs a rate-limiting middleware that allows a specified number of requests every minute.
@@ -143,11 +265,11 @@ impl GovernorMiddleware {
         T: TryInto<NonZeroU32>,
         T::Error: Error + Send + Sync + 'static,
     {
-        Ok(Self {
-            limiter: Arc::new(RateLimiter::<String, _, _>::keyed(Quota::per_minute(
-                times.try_into()?,
-            ))),
-        })
+        Ok(Self::new(
+            Quota::per_minute(times.try_into()?),
+            Arc::new(OriginKey),
+            DefaultClock::default(),
+        ))
     }
 
     /// Constructs a rate-limiting middleware that allows a specified number of requests every hour.
@@ -175,57 +297,2289 @@ impl GovernorMiddleware {
         T: TryInto<NonZeroU32>,
         T::Error: Error + Send + Sync + 'static,
     {
-        Ok(Self {
-            limiter: Arc::new(RateLimiter::<String, _, _>::keyed(Quota::per_hour(
-                times.try_into()?,
-            ))),
-        })
+        Ok(Self::new(
+            Quota::per_hour(times.try_into()?),
+            Arc::new(OriginKey),
+            DefaultClock::default(),
+        ))
     }
 }
 
-#[surf::utils::async_trait]
-impl surf::middleware::Middleware for GovernorMiddleware {
-    async fn handle(
-        &self,
-        req: Request,
-        client: Client,
-        next: Next<'_>,
-    ) -> std::result::Result<surf::Response, http_types::Error> {
-        match self
-            .limiter
-            .check_key(&req.url().host_str().unwrap().to_string())
-        {
-            Ok(_) => Ok(next.run(req, client).await?),
-            Err(negative) => {
-                let wait_time = negative.wait_time_from(CLOCK.now());
-                let mut res = Response::new(StatusCode::TooManyRequests);
-                res.insert_header(headers::RETRY_AFTER, wait_time.as_secs().to_string());
-                Ok(res.try_into()?)
-            }
+impl<K: RateLimitKey, C: RateLimitClock> GovernorMiddleware<K, C> {
+    fn new(quota: Quota, extractor: Arc<dyn KeyExtractor<Key = K>>, clock: C) -> Self {
+        Self {
+            rules: Arc::new(RwLock::new(Rules::new(quota, clock.clone()))),
+            checks: Arc::new(Mutex::new(())),
+            extractor,
+            on_missing_key: MissingKey::default(),
+            on_limit: OnLimit::default(),
+            jitter: Jitter::default(),
+            backoff: None,
+            budgets: None,
+            aimd: None,
+            in_flight: None,
+            costs: Arc::default(),
+            marker: default_marker(),
+            rate_limit_headers: false,
+            rejection: RejectionResponse::default(),
+            rejection_handler: None,
+            clock,
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::GovernorMiddleware;
-    use surf::{http::Method, Client, Request};
-    use url::Url;
-    use wiremock::{matchers::method, Mock, MockServer, ResponseTemplate};
-    #[async_std::test]
-    async fn limits_requests() -> surf::Result<()> {
-        let mock_server = MockServer::start().await;
-        let m = Mock::given(method("GET"))
-            .respond_with(ResponseTemplate::new(200).set_body_string("Hello!".to_string()))
-            .expect(1);
-        let _mock_guard = mock_server.register_as_scoped(m).await;
-        let url = format!("{}/", &mock_server.uri());
-        let req = Request::new(Method::Get, Url::parse(&url).unwrap());
-        let client = Client::new().with(GovernorMiddleware::per_second(1)?);
-        let good_res = client.send(req.clone()).await?;
-        assert_eq!(good_res.status(), 200);
-        let wait_res = client.send(req).await?;
-        assert_eq!(wait_res.status(), 429);
+    fn rules(&self) -> RwLockReadGuard<'_, Rules<K, C>> {
+        self.rules.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn rules_mut(&self) -> RwLockWriteGuard<'_, Rules<K, C>> {
+        self.rules.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Replaces the rules, and any adapted rates with ones starting at the new default.
+    fn set_rules(&mut self, rules: Rules<K, C>) {
+        if let Some(aimd) = &self.aimd {
+            let quota = rules.default_quotas()[0];
+            self.aimd = Some(Arc::new(aimd.requota(quota)));
+        }
+        self.rules = Arc::new(RwLock::new(rules));
+    }
+
+    /// Sets the maximum number of requests that may be sent at once, replacing the limiter.
+    ///
+    /// By default the burst size is equal to the number of requests allowed per period.
+    /// The rate at which capacity is replenished is not changed by this.
+    ///
+    /// Returns an error if `burst` can't be converted into a [`NonZeroU32`].
+    ///
+    /// # Example
+    /// This constructs a client with a governor set to 10 requests per minute, at most 2 at once
+    /// ```no_run
+    /// use surf_governor::GovernorMiddleware;
+    /// use surf::{Client, Request, http::Method};
+    /// use url::Url;
+    ///
+    /// #[async_std::main]
+    /// async fn main() -> surf::Result<()> {
+    ///     let req = Request::new(Method::Get, Url::parse("https://example.api")?);
+    ///     // Construct Surf client with a governor
+    ///     let client = Client::new().with(GovernorMiddleware::per_minute(10)?.with_burst(2)?);
+    ///     let res = client.send(req).await?;
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn with_burst<T>(mut self, burst: T) -> Result<Self>
+    where
+        T: TryInto<NonZeroU32>,
+        T::Error: Error + Send + Sync + 'static,
+    {
+        let mut rules = self.rules().rekey(self.clock.clone());
+        let mut quotas = rules.default_quotas().to_vec();
+        quotas[0] = quotas[0].allow_burst(burst.try_into()?);
+        rules.set_default_quotas(quotas);
+        self.set_rules(rules);
+        Ok(self)
+    }
+
+    /// Additionally limits requests by `quota`, replacing the limiter.
+    ///
+    /// A request is only allowed when every quota allows it, and a rejection reports
+    /// the longest wait among the quotas that rejected it. This expresses limits like
+    /// "10 per second and 5000 per hour".
+    ///
+    /// # Example
+    /// This constructs a client with a governor set to 10 requests per second and 5000 per hour
+    /// ```no_run
+    /// use surf_governor::GovernorMiddleware;
+    /// use surf::{Client, Request, http::Method};
+    /// use url::Url;
+    ///
+    /// use governor::Quota;
+    /// use std::num::NonZeroU32;
+    ///
+    /// #[async_std::main]
+    /// async fn main() -> surf::Result<()> {
+    ///     let req = Request::new(Method::Get, Url::parse("https://example.api")?);
+    ///     // Construct Surf client with a governor
+    ///     let governor = GovernorMiddleware::per_second(10)?
+    ///         .with_window(Quota::per_hour(NonZeroU32::new(5000).unwrap()));
+    ///     let client = Client::new().with(governor);
+    ///     let res = client.send(req).await?;
+    ///     Ok(())
+    /// }
+    /// ```
+    #[must_use]
+    pub fn with_window(mut self, quota: Quota) -> Self {
+        let mut rules = self.rules().rekey(self.clock.clone());
+        let mut quotas = rules.default_quotas().to_vec();
+        quotas.push(quota);
+        rules.set_default_quotas(quotas);
+        self.set_rules(rules);
+        self
+    }
+
+    /// Limits all requests with a single bucket regardless of their destination, replacing the limiter.
+    ///
+    /// Requests to hosts with a quota of their own are still limited by that quota
+    /// instead. No key is extracted for requests limited by the shared bucket.
+    ///
+    /// # Example
+    /// This constructs a client that sends at most 100 requests per second in total
+    /// ```no_run
+    /// use surf_governor::GovernorMiddleware;
+    /// use surf::{Client, Request, http::Method};
+    /// use url::Url;
+    ///
+    /// #[async_std::main]
+    /// async fn main() -> surf::Result<()> {
+    ///     let req = Request::new(Method::Get, Url::parse("https://example.api")?);
+    ///     // Construct Surf client with a governor
+    ///     let client = Client::new().with(GovernorMiddleware::per_second(100)?.global());
+    ///     let res = client.send(req).await?;
+    ///     Ok(())
+    /// }
+    /// ```
+    #[must_use]
+    pub fn global(mut self) -> Self {
+        let mut rules = self.rules().rekey(self.clock.clone());
+        rules.share_default();
+        self.set_rules(rules);
+        self
+    }
+
+    /// Sets the [`KeyExtractor`] deciding which bucket a request is counted against, replacing the limiter.
+    ///
+    /// Defaults to [`OriginKey`]. As the type of key may change, this resets any policy
+    /// set with [`GovernorMiddleware::on_missing_key`] and drops any handler set with
+    /// [`GovernorMiddleware::with_rejection_handler`].
+    ///
+    /// # Example
+    /// This constructs a client that shares one bucket between every port of a host
+    /// ```no_run
+    /// use surf_governor::{GovernorMiddleware, HostKey};
+    /// use surf::{Client, Request, http::Method};
+    /// use url::Url;
+    ///
+    /// #[async_std::main]
+    /// async fn main() -> surf::Result<()> {
+    ///     let req = Request::new(Method::Get, Url::parse("https://example.api")?);
+    ///     // Construct Surf client with a governor
+    ///     let client = Client::new().with(GovernorMiddleware::per_second(30)?.with_key_extractor(HostKey));
+    ///     let res = client.send(req).await?;
+    ///     Ok(())
+    /// }
+    /// ```
+    #[must_use]
+    pub fn with_key_extractor<E: KeyExtractor>(
+        self,
+        extractor: E,
+    ) -> GovernorMiddleware<E::Key, C> {
+        GovernorMiddleware {
+            rules: Arc::new(RwLock::new(self.rules().rekey(self.clock.clone()))),
+            checks: Arc::new(Mutex::new(())),
+            extractor: Arc::new(extractor),
+            on_missing_key: MissingKey::default(),
+            on_limit: self.on_limit,
+            jitter: self.jitter,
+            backoff: self.backoff.as_ref().map(|_| Arc::default()),
+            budgets: self.budgets.as_ref().map(|_| Arc::default()),
+            aimd: self
+                .aimd
+                .as_ref()
+                .map(|aimd| Arc::new(aimd.rekey(self.clock.clone()))),
+            in_flight: self
+                .in_flight
+                .as_ref()
+                .map(|in_flight| Arc::new(in_flight.rekey())),
+            costs: self.costs.clone(),
+            marker: self.marker.clone(),
+            rate_limit_headers: self.rate_limit_headers,
+            rejection: self.rejection.clone(),
+            rejection_handler: None,
+            clock: self.clock.clone(),
+        }
+    }
+
+    /// Sets the [`Clock`](governor::clock::Clock) the middleware measures time with, replacing the limiter.
+    ///
+    /// Defaults to [`DefaultClock`]. A [`FakeRelativeClock`](governor::clock::FakeRelativeClock)
+    /// only moves when advanced, which lets tests assert exactly which requests are
+    /// allowed without sleeping. Waiting with [`OnLimit::Wait`] still takes real time.
+    ///
+    /// # Example
+    /// This constructs a client whose limiter only moves when the test advances its clock
+    /// ```no_run
+    /// use surf_governor::GovernorMiddleware;
+    /// use surf::{Client, Request, http::Method};
+    /// use url::Url;
+    ///
+    /// use governor::clock::FakeRelativeClock;
+    /// use std::time::Duration;
+    ///
+    /// #[async_std::main]
+    /// async fn main() -> surf::Result<()> {
+    ///     let clock = FakeRelativeClock::default();
+    ///     // Construct Surf client with a governor
+    ///     let client = Client::new().with(GovernorMiddleware::per_second(1)?.with_clock(clock.clone()));
+    ///     let res = client.get("https://example.api").await?;
+    ///     assert_eq!(res.status(), 200);
+    ///     let res = client.get("https://example.api").await?;
+    ///     assert_eq!(res.status(), 429);
+    ///     clock.advance(Duration::from_secs(1));
+    ///     let res = client.get("https://example.api").await?;
+    ///     assert_eq!(res.status(), 200);
+    ///     Ok(())
+    /// }
+    /// ```
+    #[must_use]
+    pub fn with_clock<D: RateLimitClock>(self, clock: D) -> GovernorMiddleware<K, D> {
+        GovernorMiddleware {
+            rules: Arc::new(RwLock::new(self.rules().rekey(clock.clone()))),
+            checks: Arc::new(Mutex::new(())),
+            extractor: self.extractor.clone(),
+            on_missing_key: self.on_missing_key.clone(),
+            on_limit: self.on_limit,
+            jitter: self.jitter,
+            backoff: self.backoff.as_ref().map(|_| Arc::default()),
+            budgets: self.budgets.as_ref().map(|_| Arc::default()),
+            aimd: self
+                .aimd
+                .as_ref()
+                .map(|aimd| Arc::new(aimd.rekey(clock.clone()))),
+            in_flight: self
+                .in_flight
+                .as_ref()
+                .map(|in_flight| Arc::new(in_flight.rekey())),
+            costs: self.costs.clone(),
+            marker: self.marker.clone(),
+            rate_limit_headers: self.rate_limit_headers,
+            rejection: self.rejection.clone(),
+            rejection_handler: self.rejection_handler.clone(),
+            clock,
+        }
+    }
+
+    /// Makes requests wait for as long as a 429 (too many requests) or 503 (service
+    /// unavailable) response asks for in its `Retry-After` header.
+    ///
+    /// After such a response, requests with the same key are limited locally until the
+    /// indicated time, whether it is given in seconds or as an HTTP date. Requests
+    /// without a key are not affected.
+    ///
+    /// # Example
+    /// This constructs a client that backs off whenever the server asks it to
+    /// ```no_run
+    /// use surf_governor::GovernorMiddleware;
+    /// use surf::{Client, Request, http::Method};
+    /// use url::Url;
+    ///
+    /// #[async_std::main]
+    /// async fn main() -> surf::Result<()> {
+    ///     let req = Request::new(Method::Get, Url::parse("https://example.api")?);
+    ///     // Construct Surf client with a governor
+    ///     let client = Client::new().with(GovernorMiddleware::per_second(30)?.honor_retry_after());
+    ///     let res = client.send(req).await?;
+    ///     Ok(())
+    /// }
+    /// ```
+    #[must_use]
+    pub fn honor_retry_after(mut self) -> Self {
+        self.backoff = Some(Arc::default());
+        self
+    }
+
+    /// Never sends more requests than a server reports it has left, as read from the
+    /// `RateLimit` and `RateLimit-Policy`, `RateLimit-Remaining` and `RateLimit-Reset`, or
+    /// `X-RateLimit-Remaining` and `X-RateLimit-Reset` headers of its responses.
+    ///
+    /// Once the reported remaining count of a key is used up, its requests are limited
+    /// locally until the reported reset, which may be given either in seconds or as a Unix
+    /// time. Requests without a key are not affected.
+    ///
+    /// The reported budget is counted per request rather than per cell: a request takes one
+    /// unit of it whatever its [`Cost`], and a request costing nothing takes none.
+    ///
+    /// # Example
+    /// This constructs a client that follows the limits the server reports
+    /// ```no_run
+    /// use surf_governor::GovernorMiddleware;
+    /// use surf::{Client, Request, http::Method};
+    /// use url::Url;
+    ///
+    /// #[async_std::main]
+    /// async fn main() -> surf::Result<()> {
+    ///     let req = Request::new(Method::Get, Url::parse("https://example.api")?);
+    ///     // Construct Surf client with a governor
+    ///     let client = Client::new().with(GovernorMiddleware::per_second(30)?.follow_rate_limit_headers());
+    ///     let res = client.send(req).await?;
+    ///     Ok(())
+    /// }
+    /// ```
+    #[must_use]
+    pub fn follow_rate_limit_headers(mut self) -> Self {
+        self.budgets = Some(Arc::default());
+        self
+    }
+
+    /// Adapts the rate of each key to how its server responds, starting at the default
+    /// quota and staying within the floor and ceiling of `aimd`.
+    ///
+    /// The adapted rate replaces the first default quota, while hosts with their own
+    /// quota and layers are limited as before. Requests without a key are not affected.
+    ///
+    /// # Errors
+    /// Returns a [`GovernorConfigError`] if the floor of `aimd` is above its ceiling or its
+    /// decrease is not between 0 and 1.
+    ///
+    /// # Example
+    /// This constructs a client starting at 10 requests per second, adapting between 1 and 50
+    /// ```no_run
+    /// use surf_governor::{Aimd, GovernorMiddleware};
+    /// use surf::{Client, Request, http::Method};
+    /// use url::Url;
+    ///
+    /// use governor::Quota;
+    /// use std::num::NonZeroU32;
+    ///
+    /// #[async_std::main]
+    /// async fn main() -> surf::Result<()> {
+    ///     let req = Request::new(Method::Get, Url::parse("https://example.api")?);
+    ///     let quota = |count| Quota::per_second(NonZeroU32::new(count).unwrap());
+    ///     let aimd = Aimd::new(quota(1), quota(50));
+    ///     // Construct Surf client with a governor
+    ///     let client = Client::new().with(GovernorMiddleware::per_second(10)?.with_aimd(aimd)?);
+    ///     let res = client.send(req).await?;
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn with_aimd(mut self, aimd: Aimd) -> Result<Self> {
+        aimd.validate()?;
+        let quota = self.rules().default_quotas()[0];
+        self.aimd = Some(Arc::new(Adaptive::new(aimd, quota, self.clock.clone())));
+        Ok(self)
+    }
+
+    /// Limits the number of requests in flight for each key to `max`, on top of the rate.
+    ///
+    /// A request beyond the limit waits for another one to complete or is rejected, as set
+    /// by [`GovernorMiddleware::on_limit`]. A request completes once its response headers
+    /// or an error come back. Requests without a key are not affected. As there is no
+    /// telling when a request completes, rejected requests are told to wait 100ms.
+    ///
+    /// # Example
+    /// This constructs a client with at most 4 requests in flight per origin
+    /// ```no_run
+    /// use surf_governor::GovernorMiddleware;
+    /// use surf::{Client, Request, http::Method};
+    /// use url::Url;
+    ///
+    /// #[async_std::main]
+    /// async fn main() -> surf::Result<()> {
+    ///     let req = Request::new(Method::Get, Url::parse("https://example.api")?);
+    ///     // Construct Surf client with a governor
+    ///     let client = Client::new().with(GovernorMiddleware::per_second(30)?.with_max_in_flight(4)?);
+    ///     let res = client.send(req).await?;
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn with_max_in_flight<T>(mut self, max: T) -> Result<Self>
+    where
+        T: TryInto<NonZeroU32>,
+        T::Error: Error + Send + Sync + 'static,
+    {
+        self.in_flight = Some(Arc::new(InFlight::new(max.try_into()?)));
+        Ok(self)
+    }
+
+    /// Makes requests cost the number of cells returned by `cost` instead of one.
+    ///
+    /// Costs set per route with [`GovernorMiddleware::with_route_cost`] or attached to a
+    /// request as a [`Cost`] extension take precedence.
+    ///
+    /// # Example
+    /// This constructs a client counting each search request as 5 requests
+    /// ```no_run
+    /// use surf_governor::GovernorMiddleware;
+    /// use surf::{Client, Request, http::Method};
+    /// use url::Url;
+    ///
+    /// #[async_std::main]
+    /// async fn main() -> surf::Result<()> {
+    ///     let req = Request::new(Method::Get, Url::parse("https://example.api/search?q=rust")?);
+    ///     // Construct Surf client with a governor
+    ///     let governor = GovernorMiddleware::per_second(30)?
+    ///         .with_cost(|req| if req.url().path() == "/search" { 5 } else { 1 });
+    ///     let client = Client::new().with(governor);
+    ///     let res = client.send(req).await?;
+    ///     Ok(())
+    /// }
+    /// ```
+    #[must_use]
+    pub fn with_cost<F>(mut self, cost: F) -> Self
+    where
+        F: Fn(&Request) -> u32 + Send + Sync + 'static,
+    {
+        Arc::make_mut(&mut self.costs).set_fn(Arc::new(cost));
+        self
+    }
+
+    /// Makes requests with `method` whose path starts with the segments of `path` cost
+    /// `cost` cells instead of one.
+    ///
+    /// A request matching several routes costs as much as the first one set. Costs
+    /// attached to a request as a [`Cost`] extension take precedence.
+    ///
+    /// # Example
+    /// This constructs a client counting each `POST /v1/bulk` as 10 requests
+    /// ```no_run
+    /// use surf_governor::GovernorMiddleware;
+    /// use surf::{Client, Request, http::Method};
+    /// use url::Url;
+    ///
+    /// #[async_std::main]
+    /// async fn main() -> surf::Result<()> {
+    ///     let req = Request::new(Method::Post, Url::parse("https://example.api/v1/bulk")?);
+    ///     // Construct Surf client with a governor
+    ///     let governor = GovernorMiddleware::per_second(30)?.with_route_cost(Method::Post, "/v1/bulk", 10);
+    ///     let client = Client::new().with(governor);
+    ///     let res = client.send(req).await?;
+    ///     Ok(())
+    /// }
+    /// ```
+    #[must_use]
+    pub fn with_route_cost(mut self, method: Method, path: impl Into<String>, cost: u32) -> Self {
+        Arc::make_mut(&mut self.costs).set_route(method, path.into(), cost);
+        self
+    }
+
+    /// Limits requests to `host` with `quota` instead of the default quota, replacing
+    /// any quota previously set for it.
+    ///
+    /// `host` may be a host (`example.com`), a host and port (`example.com:8080`), an
+    /// origin (`https://example.com`) or a host pattern (`*.s3.amazonaws.com`,
+    /// `api.*.example.com`). In a pattern, `*` stands for any single label, except as
+    /// the first label where it stands for one or more labels.
+    ///
+    /// When several match a request, the origin is preferred over the host and port,
+    /// which is preferred over the host, which is preferred over any pattern. Among
+    /// patterns, the one with the most literal labels wins.
+    ///
+    /// This takes effect for every clone of the middleware, including ones already
+    /// added to a client.
+    ///
+    /// # Example
+    /// This constructs a client limited to 10 requests per second, except for `api.github.com`
+    /// ```no_run
+    /// use surf_governor::GovernorMiddleware;
+    /// use surf::{Client, Request, http::Method};
+    /// use url::Url;
+    ///
+    /// use governor::Quota;
+    /// use std::num::NonZeroU32;
+    ///
+    /// #[async_std::main]
+    /// async fn main() -> surf::Result<()> {
+    ///     let req = Request::new(Method::Get, Url::parse("https://api.github.com")?);
+    ///     // Construct Surf client with a governor
+    ///     let governor = GovernorMiddleware::per_second(10)?;
+    ///     governor.set_host_quota("api.github.com", Quota::per_hour(NonZeroU32::new(5000).unwrap()));
+    ///     let client = Client::new().with(governor.clone());
+    ///     let res = client.send(req).await?;
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn set_host_quota(&self, host: &str, quota: Quota) {
+        self.set_host_quotas(host, [quota]);
+    }
+
+    /// Limits requests to `host` with every one of `quotas` instead of the default quotas,
+    /// replacing any quotas previously set for it.
+    ///
+    /// Works like [`GovernorMiddleware::set_host_quota`], except that a request is only
+    /// allowed when every quota allows it. Requests to a host with no quotas at all are
+    /// not limited by host.
+    pub fn set_host_quotas(&self, host: &str, quotas: impl IntoIterator<Item = Quota>) {
+        self.rules_mut().insert(host, quotas.into_iter().collect());
+    }
+
+    /// Makes requests to any of `hosts` draw from one shared bucket limited by `quota`,
+    /// replacing any group previously named `name`.
+    ///
+    /// `hosts` accepts the same forms as [`GovernorMiddleware::set_host_quota`]. The
+    /// bucket is shared by every request to the group, whatever its key.
+    ///
+    /// # Example
+    /// This constructs a client that limits the API and upload hosts of a provider together
+    /// ```no_run
+    /// use surf_governor::GovernorMiddleware;
+    /// use surf::{Client, Request, http::Method};
+    /// use url::Url;
+    ///
+    /// use governor::Quota;
+    /// use std::num::NonZeroU32;
+    ///
+    /// #[async_std::main]
+    /// async fn main() -> surf::Result<()> {
+    ///     let req = Request::new(Method::Get, Url::parse("https://api.example.com")?);
+    ///     // Construct Surf client with a governor
+    ///     let governor = GovernorMiddleware::per_second(10)?;
+    ///     governor.set_host_group(
+    ///         "example",
+    ///         &["api.example.com", "uploads.example.com", "*.cdn.example.com"],
+    ///         Quota::per_minute(NonZeroU32::new(100).unwrap()),
+    ///     );
+    ///     let client = Client::new().with(governor.clone());
+    ///     let res = client.send(req).await?;
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn set_host_group<H: AsRef<str>>(&self, name: &str, hosts: &[H], quota: Quota) {
+        self.rules_mut()
+            .insert_group(name, hosts.iter().map(AsRef::as_ref), vec![quota]);
+    }
+
+    /// Removes the group `name`, returning whether there was one.
+    ///
+    /// Requests to its hosts are limited by the default quota afterwards.
+    pub fn remove_host_group(&self, name: &str) -> bool {
+        self.rules_mut().remove_group(name)
+    }
+
+    /// Enforces `layer` together with the host quotas and any other layers.
+    ///
+    /// This takes effect for every clone of the middleware, including ones already
+    /// added to a client.
+    pub fn add_layer(&self, layer: Layer) {
+        self.rules_mut().add_layer(layer);
+    }
+
+    /// Removes the quota set for `host`, returning whether there was one.
+    ///
+    /// Requests to `host` are limited by the default quota afterwards.
+    pub fn remove_host_quota(&self, host: &str) -> bool {
+        self.rules_mut().remove(host)
+    }
+
+    /// Sets what happens to a request the key extractor produces no key for,
+    /// e.g. a `file:` or `data:` URL without a host.
+    ///
+    /// # Example
+    /// This constructs a client that limits requests without a host under a shared key
+    /// ```no_run
+    /// use surf_governor::{GovernorMiddleware, MissingKey};
+    /// use surf::{Client, Request, http::Method};
+    /// use url::Url;
+    ///
+    /// #[async_std::main]
+    /// async fn main() -> surf::Result<()> {
+    ///     let req = Request::new(Method::Get, Url::parse("https://example.api")?);
+    ///     // Construct Surf client with a governor
+    ///     let governor = GovernorMiddleware::per_second(30)?
+    ///         .on_missing_key(MissingKey::Fallback("hostless".to_string()));
+    ///     let client = Client::new().with(governor);
+    ///     let res = client.send(req).await?;
+    ///     Ok(())
+    /// }
+    /// ```
+    #[must_use]
+    pub fn on_missing_key(mut self, on_missing_key: MissingKey<K>) -> Self {
+        self.on_missing_key = on_missing_key;
+        self
+    }
+
+    /// Sets what happens to a request once the rate limit has been reached.
+    ///
+    /// # Example
+    /// This constructs a client that waits for the governor instead of receiving a 429
+    /// ```no_run
+    /// use surf_governor::{GovernorMiddleware, OnLimit};
+    /// use surf::{Client, Request, http::Method};
+    /// use url::Url;
+    ///
+    /// #[async_std::main]
+    /// async fn main() -> surf::Result<()> {
+    ///     let req = Request::new(Method::Get, Url::parse("https://example.api")?);
+    ///     // Construct Surf client with a governor
+    ///     let client = Client::new().with(GovernorMiddleware::per_second(30)?.on_limit(OnLimit::Wait));
+    ///     let res = client.send(req).await?;
+    ///     Ok(())
+    /// }
+    /// ```
+    #[must_use]
+    pub fn on_limit(mut self, on_limit: OnLimit) -> Self {
+        self.on_limit = on_limit;
+        self
+    }
+
+    /// Sets the [`Jitter`] added to every delay the middleware imposes.
+    ///
+    /// # Example
+    /// This constructs a client that waits an extra 100 to 600 milliseconds whenever it is limited
+    /// ```no_run
+    /// use surf_governor::{GovernorMiddleware, Jitter, OnLimit};
+    /// use surf::{Client, Request, http::Method};
+    /// use url::Url;
+    ///
+    /// use std::time::Duration;
+    ///
+    /// #[async_std::main]
+    /// async fn main() -> surf::Result<()> {
+    ///     let req = Request::new(Method::Get, Url::parse("https://example.api")?);
+    ///     // Construct Surf client with a governor
+    ///     let governor = GovernorMiddleware::per_second(30)?
+    ///         .on_limit(OnLimit::Wait)
+    ///         .with_jitter(Jitter::new(Duration::from_millis(100), Duration::from_millis(500)));
+    ///     let client = Client::new().with(governor);
+    ///     let res = client.send(req).await?;
+    ///     Ok(())
+    /// }
+    /// ```
+    #[must_use]
+    pub fn with_jitter(mut self, jitter: Jitter) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Sets the header marking the rejection responses of the middleware, which is
+    /// `X-Governor-Limited: local` by default.
+    ///
+    /// Those responses also carry a [`RateLimited`] extension, whether marked by a header or not.
+    ///
+    /// # Example
+    /// This constructs a client marking its own rejection responses with `X-Throttled: client`
+    /// ```no_run
+    /// use surf_governor::{GovernorMiddleware, RateLimited};
+    /// use surf::{Client, Request, http::Method};
+    /// use url::Url;
+    ///
+    /// #[async_std::main]
+    /// async fn main() -> surf::Result<()> {
+    ///     let req = Request::new(Method::Get, Url::parse("https://example.api")?);
+    ///     // Construct Surf client with a governor
+    ///     let governor = GovernorMiddleware::per_second(30)?.with_marker_header("X-Throttled", "client".parse()?);
+    ///     let client = Client::new().with(governor);
+    ///     let res = client.send(req).await?;
+    ///     if let Some(limited) = res.ext::<RateLimited>() {
+    ///         println!("limited locally for {:?}", limited.wait());
+    ///     }
+    ///     Ok(())
+    /// }
+    /// ```
+    #[must_use]
+    pub fn with_marker_header(mut self, name: impl Into<HeaderName>, value: HeaderValue) -> Self {
+        self.marker = Some((name.into(), value));
+        self
+    }
+
+    /// Leaves the rejection responses of the middleware without a marker header.
+    #[must_use]
+    pub fn without_marker_header(mut self) -> Self {
+        self.marker = None;
+        self
+    }
+
+    /// Sets the responses the middleware rejects requests with, which are empty 429 (too
+    /// many requests) responses by default.
+    ///
+    /// The status is also the one of the [`RateLimited`] errors returned with
+    /// [`OnLimit::Error`].
+    ///
+    /// # Example
+    /// This constructs a client rejecting requests with RFC 7807 problem details
+    /// ```no_run
+    /// use surf_governor::{GovernorMiddleware, RejectionBody, RejectionResponse};
+    /// use surf::{Client, Request, http::{Method, StatusCode}};
+    /// use url::Url;
+    ///
+    /// #[async_std::main]
+    /// async fn main() -> surf::Result<()> {
+    ///     let req = Request::new(Method::Get, Url::parse("https://example.api")?);
+    ///     let rejection = RejectionResponse::new(StatusCode::TooManyRequests).body(RejectionBody::Problem);
+    ///     // Construct Surf client with a governor
+    ///     let client = Client::new().with(GovernorMiddleware::per_second(30)?.with_rejection_response(rejection));
+    ///     let res = client.send(req).await?;
+    ///     Ok(())
+    /// }
+    /// ```
+    #[must_use]
+    pub fn with_rejection_response(mut self, rejection: RejectionResponse) -> Self {
+        self.rejection = rejection;
+        self
+    }
+
+    /// Builds the responses the middleware rejects requests with by calling `handler` with
+    /// the request and why it was rejected, instead of using the rejection response.
+    ///
+    /// Those responses still carry the marker header and [`RateLimited`] extension of the
+    /// middleware, and a `Retry-After` header and any rate limit headers set with
+    /// [`GovernorMiddleware::with_rate_limit_headers`] unless `handler` sets them itself.
+    ///
+    /// # Example
+    /// This constructs a client rejecting requests with a message naming the limited key
+    /// ```no_run
+    /// use surf_governor::GovernorMiddleware;
+    /// use surf::{Client, Request, http::{Method, Response, StatusCode}};
+    /// use url::Url;
+    ///
+    /// #[async_std::main]
+    /// async fn main() -> surf::Result<()> {
+    ///     let req = Request::new(Method::Get, Url::parse("https://example.api")?);
+    ///     // Construct Surf client with a governor
+    ///     let governor = GovernorMiddleware::per_second(30)?.with_rejection_handler(|req, limited| {
+    ///         let mut res = Response::new(StatusCode::TooManyRequests);
+    ///         res.set_body(format!("{} {} is limited: {}", req.method(), req.url(), limited));
+    ///         res
+    ///     });
+    ///     let client = Client::new().with(governor);
+    ///     let res = client.send(req).await?;
+    ///     Ok(())
+    /// }
+    /// ```
+    #[must_use]
+    pub fn with_rejection_handler<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(&Request, &RateLimited<K>) -> Response + Send + Sync + 'static,
+    {
+        self.rejection_handler = Some(Handler::new(handler));
+        self
+    }
+
+    /// Adds precise rate limit information to the rejection responses of the middleware.
+    ///
+    /// Besides the `Retry-After` header in whole seconds, those responses then carry the
+    /// wait in milliseconds in a `Retry-After-Ms` header. When a quota rejected the request,
+    /// they also carry its burst size in `RateLimit-Limit`, the cells it has left in
+    /// `RateLimit-Remaining` and the seconds until it is full again in `RateLimit-Reset`.
+    ///
+    /// # Example
+    /// This constructs a client reporting how long to wait in milliseconds
+    /// ```no_run
+    /// use surf_governor::GovernorMiddleware;
+    /// use surf::{Client, Request, http::Method};
+    /// use url::Url;
+    ///
+    /// #[async_std::main]
+    /// async fn main() -> surf::Result<()> {
+    ///     let req = Request::new(Method::Get, Url::parse("https://example.api")?);
+    ///     // Construct Surf client with a governor
+    ///     let client = Client::new().with(GovernorMiddleware::per_second(30)?.with_rate_limit_headers());
+    ///     let res = client.send(req).await?;
+    ///     if let Some(millis) = res.header("Retry-After-Ms") {
+    ///         println!("retry after {} ms", millis);
+    ///     }
+    ///     Ok(())
+    /// }
+    /// ```
+    #[must_use]
+    pub fn with_rate_limit_headers(mut self) -> Self {
+        self.rate_limit_headers = true;
+        self
+    }
+
+    /// Takes one permit for `key` from the limiters, or returns how long it has to wait,
+    /// for traffic that does not go through a [`Client`].
+    ///
+    /// Permits count against the same buckets as requests with the same key: the default
+    /// quotas or the rate adapted by [`GovernorMiddleware::with_aimd`], the layers applying
+    /// to every request, and what upstream servers asked for. Host quotas and layers
+    /// limited to a route are not involved, as they apply to requests by their URL, see
+    /// [`GovernorMiddleware::check_url`] to count permits against them.
+    ///
+    /// # Example
+    /// This counts a websocket message against the budget of the origin it is sent to
+    /// ```no_run
+    /// use surf_governor::GovernorMiddleware;
+    ///
+    /// # fn main() -> surf::Result<()> {
+    /// let governor = GovernorMiddleware::per_second(30)?;
+    /// match governor.check(&"https://example.api".to_owned()) {
+    ///     Ok(()) => { /* send the message */ }
+    ///     Err(limited) => println!("retry after {:?}", limited.wait()),
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn check(&self, key: &K) -> std::result::Result<(), RateLimited<K>> {
+        self.check_n(key, 1)
+            .expect("every limiter allows at least one permit")
+    }
+
+    /// Takes `n` permits for `key` at once from the limiters, like
+    /// [`GovernorMiddleware::check`].
+    ///
+    /// Returns an error if `n` is more than the burst size of a limiter, as those permits
+    /// can never be taken at once.
+    ///
+    /// A budget reported by the server counts the `n` permits as a single request.
+    pub fn check_n(
+        &self,
+        key: &K,
+        n: u32,
+    ) -> std::result::Result<std::result::Result<(), RateLimited<K>>, ExceedsCapacityError> {
+        let checked = self.permits(key, n)?;
+        Ok(checked.map_err(|wait| RateLimited::new(Some(key.clone()), wait.time, wait.quota)))
+    }
+
+    /// Waits until one permit for `key` can be taken from the limiters, and takes it.
+    ///
+    /// See [`GovernorMiddleware::check`] for the buckets permits count against.
+    ///
+    /// # Example
+    /// This waits for the budget of an origin before writing to a raw TCP connection
+    /// ```no_run
+    /// use surf_governor::GovernorMiddleware;
+    ///
+    /// #[async_std::main]
+    /// async fn main() -> surf::Result<()> {
+    ///     let governor = GovernorMiddleware::per_second(30)?;
+    ///     governor.acquire(&"https://example.api".to_owned()).await;
+    ///     // write to the connection
+    ///     Ok(())
+    /// }
+    /// ```
+    pub async fn acquire(&self, key: &K) {
+        self.acquire_n(key, 1)
+            .await
+            .expect("every limiter allows at least one permit");
+    }
+
+    /// Waits until `n` permits for `key` can be taken at once from the limiters, and takes
+    /// them.
+    ///
+    /// Returns an error if `n` is more than the burst size of a limiter, as those permits
+    /// can never be taken at once.
+    ///
+    /// A budget reported by the server counts the `n` permits as a single request.
+    pub async fn acquire_n(
+        &self,
+        key: &K,
+        n: u32,
+    ) -> std::result::Result<(), ExceedsCapacityError> {
+        while let Err(wait) = self.permits(key, n)? {
+            Delay::new(wait.time).await;
+        }
+        Ok(())
+    }
+
+    /// Takes `n` permits from the limiters of a `GET` request to `url`, or returns how long
+    /// they have to wait, for traffic that does not go through a [`Client`].
+    ///
+    /// Permits count against the same buckets as such a request, including the quota of
+    /// its host, host pattern or host group, and its key is extracted the same way.
+    /// Returns an error if it has no key and [`MissingKey::Error`] is set, or if `n` is
+    /// more than the burst size of a limiter.
+    ///
+    /// # Example
+    /// This counts a websocket message against the quota of its host
+    /// ```no_run
+    /// use surf_governor::GovernorMiddleware;
+    /// use governor::Quota;
+    /// use surf::Url;
+    ///
+    /// use std::num::NonZeroU32;
+    ///
+    /// # fn main() -> surf::Result<()> {
+    /// let governor = GovernorMiddleware::per_second(30)?;
+    /// governor.set_host_quota("api.example", Quota::per_second(NonZeroU32::new(5).unwrap()));
+    /// match governor.check_url(&Url::parse("wss://api.example/socket")?, 1)? {
+    ///     Ok(()) => { /* send the message */ }
+    ///     Err(limited) => println!("retry after {:?}", limited.wait()),
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn check_url(&self, url: &Url, n: u32) -> Result<std::result::Result<(), RateLimited<K>>> {
+        let (key, checked) = self.url_permits(url, n)?;
+        Ok(checked.map_err(|wait| RateLimited::new(key, wait.time, wait.quota)))
+    }
+
+    /// Waits until `n` permits can be taken at once from the limiters of a `GET` request
+    /// to `url`, and takes them.
+    ///
+    /// See [`GovernorMiddleware::check_url`] for the buckets permits count against and the
+    /// errors returned.
+    pub async fn acquire_url(&self, url: &Url, n: u32) -> Result<()> {
+        while let (_, Err(wait)) = self.url_permits(url, n)? {
+            Delay::new(wait.time).await;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the default header marking the rejection responses of the middleware.
+fn default_marker() -> Option<(HeaderName, HeaderValue)> {
+    let value = "local".parse().expect("`local` is a valid header value");
+    Some(("x-governor-limited".into(), value))
+}
+
+impl<K: RateLimitKey, C: RateLimitClock> GovernorMiddleware<K, C> {
+    /// Returns the key of `req`, applying the missing key policy if there is none.
+    fn key(&self, req: &Request) -> std::result::Result<Option<K>, http_types::Error> {
+        match (self.extractor.extract(req), &self.on_missing_key) {
+            (Some(key), _) => Ok(Some(key)),
+            (None, MissingKey::PassThrough) => Ok(None),
+            (None, MissingKey::Fallback(key)) => Ok(Some(key.clone())),
+            (None, MissingKey::Error) => Err(http_types::Error::new(
+                StatusCode::BadRequest,
+                MissingKeyError::new(req.url().clone()),
+            )),
+        }
+    }
+
+    /// Rejects `req` with `key` that has to `wait`, either with a marked response or a
+    /// [`RateLimited`] error.
+    fn reject(
+        &self,
+        req: &Request,
+        key: Option<K>,
+        wait: Wait,
+    ) -> std::result::Result<surf::Response, http_types::Error> {
+        let limited = RateLimited::new(key, wait.time, wait.quota);
+        if self.on_limit == OnLimit::Error {
+            return Err(http_types::Error::new(self.rejection.status(), limited));
+        }
+        let mut res = match &self.rejection_handler {
+            Some(handler) => handler.respond(req, &limited),
+            None => self.rejection.respond(&limited),
+        };
+        self.add_wait_headers(&mut res, &wait, self.rejection_handler.is_none());
+        if let Some((name, value)) = &self.marker {
+            res.insert_header(name, value.clone());
+        }
+        res.ext_mut().insert(limited);
+        Ok(res.into())
+    }
+
+    /// Adds the `Retry-After` header and any rate limit headers for `wait` to `res`,
+    /// replacing those it already has only if `replace` is set.
+    fn add_wait_headers(&self, res: &mut Response, wait: &Wait, replace: bool) {
+        let mut add = |name: &str, value: String| {
+            if replace || res.header(name).is_none() {
+                res.insert_header(name, value);
+            }
+        };
+        add(
+            headers::RETRY_AFTER.as_str(),
+            seconds(wait.time).max(1).to_string(),
+        );
+        if self.rate_limit_headers {
+            add("retry-after-ms", millis(wait.time).to_string());
+        }
+        if let (Some(quota), Some((remaining, reset))) = (wait.quota, wait.state) {
+            add("ratelimit-limit", quota.burst_size().to_string());
+            add("ratelimit-remaining", remaining.to_string());
+            add("ratelimit-reset", seconds(reset).to_string());
+        }
+    }
+
+    /// Returns the key of `req` and the buckets it is counted against.
+    fn targets(&self, req: &Request) -> Result<Targets<K, C>> {
+        let per_key = self.backoff.is_some()
+            || self.budgets.is_some()
+            || self.aimd.is_some()
+            || self.in_flight.is_some();
+        let key = if per_key { self.key(req)? } else { None };
+        let aimd = self.aimd.as_deref().zip(key.as_ref());
+        let limiters = self
+            .rules()
+            .limiters(req, aimd.map(|(aimd, key)| move || aimd.limiter(key)));
+        let key = if !per_key && limiters.iter().any(|l| matches!(l, Limiter::Keyed(_))) {
+            self.key(req)?
+        } else {
+            key
+        };
+        let targets = limiters
+            .into_iter()
+            .filter_map(|limiter| limiter.target(key.as_ref()))
+            .collect();
+        Ok((key, targets))
+    }
+
+    /// Takes `n` permits from the limiters of a `GET` request to `url`, returning its key
+    /// and how long they have to wait, or an error if they never can be taken.
+    fn url_permits(&self, url: &Url, n: u32) -> Result<(Option<K>, std::result::Result<(), Wait>)> {
+        let (key, targets) = self.targets(&Request::new(Method::Get, url.clone()))?;
+        let checked = self
+            .check_targets(&targets, key.as_ref(), n, self.clock.now())
+            .map_err(|err| http_types::Error::new(StatusCode::BadRequest, err))?;
+        let checked = checked.map_err(|wait| Wait {
+            time: wait.time + self.jitter.get(),
+            ..wait
+        });
+        Ok((key, checked))
+    }
+
+    /// Takes `n` permits for `key` from its default limiters, or returns how long they have
+    /// to wait, or an error if they never can be taken.
+    fn permits(
+        &self,
+        key: &K,
+        n: u32,
+    ) -> std::result::Result<std::result::Result<(), Wait>, ExceedsCapacityError> {
+        let aimd = self.aimd.as_deref();
+        let limiters = self
+            .rules()
+            .default_limiters(aimd.map(|aimd| move || aimd.limiter(key)));
+        let targets: Vec<_> = limiters
+            .into_iter()
+            .filter_map(|limiter| limiter.target(Some(key)))
+            .collect();
+        let checked = self.check_targets(&targets, Some(key), n, self.clock.now())?;
+        Ok(checked.map_err(|wait| Wait {
+            time: wait.time + self.jitter.get(),
+            ..wait
+        }))
+    }
+
+    /// Counts a request costing `cost` cells against the upstream backoff and budget of
+    /// `key` and `targets`, or returns how long it has to wait, or an error if it never can
+    /// be allowed.
+    fn check_targets(
+        &self,
+        targets: &[Target<K, C>],
+        key: Option<&K>,
+        cost: u32,
+        now: C::Instant,
+    ) -> std::result::Result<std::result::Result<(), Wait>, ExceedsCapacityError> {
+        if let (Some(backoff), Some(key)) = (&self.backoff, key) {
+            if let Some(wait_time) = backoff.wait_time(key, now) {
+                return Ok(Err(Wait::new(wait_time)));
+            }
+        }
+        let budget = self.budgets.as_deref().zip(key).filter(|_| cost > 0);
+        if let Some((budgets, key)) = budget {
+            if let Err(wait_time) = budgets.acquire(key, now) {
+                return Ok(Err(Wait::new(wait_time)));
+            }
+        }
+        let checked = match NonZeroU32::new(cost) {
+            Some(cost) => rules::check_all(targets, cost, &self.checks),
+            None => Ok(()),
+        };
+        if checked.is_err() {
+            if let Some((budgets, key)) = budget {
+                budgets.release(key);
+            }
+        }
+        match checked {
+            Ok(()) => Ok(Ok(())),
+            Err((target, Rejection::Limited(negative))) => {
+                let quota = negative.quota();
+                let state = self.rate_limit_headers.then(|| {
+                    let (remaining, full) = targets[target].state(quota);
+                    let reset = full.map_or(Duration::ZERO, |full| full.wait_time_from(now));
+                    (remaining, reset)
+                });
+                Ok(Err(Wait {
+                    time: negative.wait_time_from(now),
+                    quota: Some(quota),
+                    state,
+                }))
+            }
+            Err((_, Rejection::ExceedsCapacity(capacity))) => {
+                Err(ExceedsCapacityError::new(cost, capacity))
+            }
+        }
+    }
+}
+
+/// The key of a request and the buckets it is counted against.
+type Targets<K, C> = (Option<K>, Vec<Target<K, C>>);
+
+/// How long a rejected request has to wait, and why.
+#[derive(Debug)]
+struct Wait {
+    time: Duration,
+    /// The quota that rejected the request, if any.
+    quota: Option<Quota>,
+    /// The cells left in the limiter that rejected the request and how long until it is
+    /// full, if the middleware reports them.
+    state: Option<(u32, Duration)>,
+}
+
+impl Wait {
+    fn new(time: Duration) -> Self {
+        Self {
+            time,
+            quota: None,
+            state: None,
+        }
+    }
+}
+
+/// Returns `duration` in whole seconds, rounded up.
+fn seconds(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+/// Returns `duration` in whole milliseconds, rounded up.
+pub(crate) fn millis(duration: Duration) -> u128 {
+    (duration.as_nanos() + 999_999) / 1_000_000
+}
+
+#[surf::utils::async_trait]
+impl<K: RateLimitKey, C: RateLimitClock> surf::middleware::Middleware for GovernorMiddleware<K, C> {
+    async fn handle(
+        &self,
+        req: Request,
+        client: Client,
+        next: Next<'_>,
+    ) -> std::result::Result<surf::Response, http_types::Error> {
+        let (key, mut targets) = self.targets(&req)?;
+        let _slot = match (&self.in_flight, &key) {
+            (Some(in_flight), Some(key)) if self.on_limit == OnLimit::Wait => {
+                Some(in_flight.acquire(key).await)
+            }
+            (Some(in_flight), Some(slot_key)) => match in_flight.try_acquire(slot_key) {
+                Some(slot) => Some(slot),
+                None => {
+                    let wait = Wait::new(IN_FLIGHT_WAIT + self.jitter.get());
+                    return self.reject(&req, key, wait);
+                }
+            },
+            _ => None,
+        };
+        let cost = self.costs.of(&req);
+        loop {
+            let now = self.clock.now();
+            let checked = self
+                .check_targets(&targets, key.as_ref(), cost, now)
+                .map_err(|err| http_types::Error::new(StatusCode::BadRequest, err))?;
+            let wait = match checked {
+                Ok(()) => break,
+                Err(wait) => Wait {
+                    time: wait.time + self.jitter.get(),
+                    ..wait
+                },
+            };
+            if self.on_limit == OnLimit::Wait {
+                Delay::new(wait.time).await;
+                // The limiters may have been replaced while waiting, e.g. by AIMD.
+                targets = self.targets(&req)?.1;
+                continue;
+            }
+            return self.reject(&req, key, wait);
+        }
+        let res = next.run(req, client).await;
+        if let (Some(aimd), Some(key)) = (&self.aimd, &key) {
+            aimd.observe(key, Outcome::of(&res));
+        }
+        let res = res?;
+        if let Some(key) = key {
+            let now = self.clock.now();
+            if let Some(budgets) = &self.budgets {
+                budgets.observe(key.clone(), now, &res);
+            }
+            if let Some(backoff) = &self.backoff {
+                backoff.observe(key, now, &res);
+            }
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        Aimd, Cost, ExceedsCapacityError, GovernorConfigError, GovernorMiddleware,
+        GovernorRegistry, HeaderKey, HostKey, Jitter, Layer, MissingKey, MissingKeyError, OnLimit,
+        RateLimited, RejectionBody, RejectionResponse,
+    };
+    use governor::{clock::FakeRelativeClock, Quota};
+    use http_types::{other::RetryAfter, Response, StatusCode};
+    use std::num::NonZeroU32;
+    use std::time::{Duration, Instant, SystemTime};
+    use surf::{http::Method, Client, Config, Request};
+    use url::Url;
+    use wiremock::{
+        matchers::{any, method},
+        Mock, MockServer, ResponseTemplate,
+    };
+    #[async_std::test]
+    async fn limits_requests() -> surf::Result<()> {
+        let mock_server = MockServer::start().await;
+        let m = Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("Hello!".to_string()))
+            .expect(1);
+        let _mock_guard = mock_server.register_as_scoped(m).await;
+        let url = format!("{}/", &mock_server.uri());
+        let req = Request::new(Method::Get, Url::parse(&url).unwrap());
+        let client = Client::new().with(GovernorMiddleware::per_second(1)?);
+        let good_res = client.send(req.clone()).await?;
+        assert_eq!(good_res.status(), 200);
+        let wait_res = client.send(req).await?;
+        assert_eq!(wait_res.status(), 429);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn limits_bursts() -> surf::Result<()> {
+        let mock_server = MockServer::start().await;
+        let m = Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("Hello!".to_string()))
+            .expect(2);
+        let _mock_guard = mock_server.register_as_scoped(m).await;
+        let url = format!("{}/", &mock_server.uri());
+        let req = Request::new(Method::Get, Url::parse(&url).unwrap());
+        let client = Client::new().with(GovernorMiddleware::per_minute(10)?.with_burst(2)?);
+        for _ in 0..2 {
+            let good_res = client.send(req.clone()).await?;
+            assert_eq!(good_res.status(), 200);
+        }
+        let wait_res = client.send(req).await?;
+        assert_eq!(wait_res.status(), 429);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn allows_bursts_above_rate() -> surf::Result<()> {
+        let mock_server = MockServer::start().await;
+        let m = Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("Hello!".to_string()))
+            .expect(20);
+        let _mock_guard = mock_server.register_as_scoped(m).await;
+        let url = format!("{}/", &mock_server.uri());
+        let req = Request::new(Method::Get, Url::parse(&url).unwrap());
+        let client = Client::new().with(GovernorMiddleware::per_second(1)?.with_burst(20)?);
+        for _ in 0..20 {
+            let good_res = client.send(req.clone()).await?;
+            assert_eq!(good_res.status(), 200);
+        }
+        let wait_res = client.send(req).await?;
+        assert_eq!(wait_res.status(), 429);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn builds_from_builder() -> surf::Result<()> {
+        let mock_server = MockServer::start().await;
+        let m = Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("Hello!".to_string()))
+            .expect(2);
+        let _mock_guard = mock_server.register_as_scoped(m).await;
+        let url = format!("{}/", &mock_server.uri());
+        let req = Request::new(Method::Get, Url::parse(&url).unwrap());
+        let governor = GovernorMiddleware::builder().per_day(5).burst(2).build()?;
+        let client = Client::new().with(governor);
+        for _ in 0..2 {
+            let good_res = client.send(req.clone()).await?;
+            assert_eq!(good_res.status(), 200);
+        }
+        let wait_res = client.send(req).await?;
+        assert_eq!(wait_res.status(), 429);
+        Ok(())
+    }
+
+    #[test]
+    fn builder_rejects_invalid_configuration() {
+        let err = |builder: crate::GovernorBuilder| builder.build().unwrap_err();
+        assert_eq!(
+            err(GovernorMiddleware::builder()),
+            GovernorConfigError::MissingQuota
+        );
+        assert_eq!(
+            err(GovernorMiddleware::builder().per_second(0)),
+            GovernorConfigError::ZeroCount
+        );
+        assert_eq!(
+            err(GovernorMiddleware::builder().per(1, Duration::ZERO)),
+            GovernorConfigError::ZeroPeriod
+        );
+        assert_eq!(
+            err(GovernorMiddleware::builder().per_second(1).burst(0)),
+            GovernorConfigError::ZeroBurst
+        );
+        assert_eq!(
+            err(GovernorMiddleware::builder().per_second(1).max_in_flight(0)),
+            GovernorConfigError::ZeroMaxInFlight
+        );
+        assert_eq!(
+            err(GovernorMiddleware::builder().per(1, Duration::MAX)),
+            GovernorConfigError::Overflow {
+                period: Duration::MAX
+            }
+        );
+        assert_eq!(
+            err(GovernorMiddleware::builder().per(10, Duration::from_nanos(5))),
+            GovernorConfigError::TooFrequent {
+                count: 10,
+                period: Duration::from_nanos(5)
+            }
+        );
+        assert!(GovernorMiddleware::builder()
+            .quota(Quota::per_hour(NonZeroU32::new(3).unwrap()))
+            .build()
+            .is_ok());
+    }
+
+    #[async_std::test]
+    async fn limits_requests_per_key() -> surf::Result<()> {
+        let mock_server = MockServer::start().await;
+        let m = Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("Hello!".to_string()))
+            .expect(2);
+        let _mock_guard = mock_server.register_as_scoped(m).await;
+        let url = format!("{}/", &mock_server.uri());
+        let governor = GovernorMiddleware::builder()
+            .per_second(1)
+            .key_extractor(HeaderKey::new("X-Api-Key"))
+            .build()?;
+        let client = Client::new().with(governor);
+        let mut first = Request::new(Method::Get, Url::parse(&url).unwrap());
+        first.insert_header("X-Api-Key", "first");
+        let mut second = first.clone();
+        second.insert_header("X-Api-Key", "second");
+        assert_eq!(client.send(first.clone()).await?.status(), 200);
+        assert_eq!(client.send(second).await?.status(), 200);
+        assert_eq!(client.send(first).await?.status(), 429);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn limits_ports_independently() -> surf::Result<()> {
+        let first_server = MockServer::start().await;
+        let second_server = MockServer::start().await;
+        let m = || {
+            Mock::given(method("GET"))
+                .respond_with(ResponseTemplate::new(200).set_body_string("Hello!".to_string()))
+        };
+        let _first_guard = first_server.register_as_scoped(m().expect(1)).await;
+        let _second_guard = second_server.register_as_scoped(m().expect(1)).await;
+        let first = Request::new(Method::Get, Url::parse(&first_server.uri()).unwrap());
+        let second = Request::new(Method::Get, Url::parse(&second_server.uri()).unwrap());
+        let client = Client::new().with(GovernorMiddleware::per_second(1)?);
+        assert_eq!(client.send(first).await?.status(), 200);
+        assert_eq!(client.send(second).await?.status(), 200);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn limits_ports_together_by_host() -> surf::Result<()> {
+        let first_server = MockServer::start().await;
+        let second_server = MockServer::start().await;
+        let m = || {
+            Mock::given(method("GET"))
+                .respond_with(ResponseTemplate::new(200).set_body_string("Hello!".to_string()))
+        };
+        let _first_guard = first_server.register_as_scoped(m().expect(1)).await;
+        let _second_guard = second_server.register_as_scoped(m().expect(0)).await;
+        let first = Request::new(Method::Get, Url::parse(&first_server.uri()).unwrap());
+        let second = Request::new(Method::Get, Url::parse(&second_server.uri()).unwrap());
+        let client =
+            Client::new().with(GovernorMiddleware::per_second(1)?.with_key_extractor(HostKey));
+        assert_eq!(client.send(first).await?.status(), 200);
+        assert_eq!(client.send(second).await?.status(), 429);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn limits_hosts_with_their_own_quota() -> surf::Result<()> {
+        let mock_server = MockServer::start().await;
+        let m = Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("Hello!".to_string()))
+            .expect(4);
+        let _mock_guard = mock_server.register_as_scoped(m).await;
+        let local = Request::new(Method::Get, Url::parse(&mock_server.uri()).unwrap());
+        let loopback = Request::new(
+            Method::Get,
+            Url::parse(&mock_server.uri().replace("127.0.0.1", "localhost")).unwrap(),
+        );
+        let governor = GovernorMiddleware::builder()
+            .per_second(1)
+            .host_quota("localhost", Quota::per_second(NonZeroU32::new(2).unwrap()))
+            .build()?;
+        let client = Client::new().with(governor.clone());
+        assert_eq!(client.send(local.clone()).await?.status(), 200);
+        assert_eq!(client.send(local).await?.status(), 429);
+        assert_eq!(client.send(loopback.clone()).await?.status(), 200);
+        assert_eq!(client.send(loopback.clone()).await?.status(), 200);
+        assert_eq!(client.send(loopback.clone()).await?.status(), 429);
+        governor.set_host_quota(
+            &mock_server.uri().replace("127.0.0.1", "localhost"),
+            Quota::per_second(NonZeroU32::new(1).unwrap()),
+        );
+        assert_eq!(client.send(loopback.clone()).await?.status(), 200);
+        assert_eq!(client.send(loopback).await?.status(), 429);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn limits_requests_by_every_window() -> surf::Result<()> {
+        let mock_server = MockServer::start().await;
+        let m = Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("Hello!".to_string()))
+            .expect(1);
+        let _mock_guard = mock_server.register_as_scoped(m).await;
+        let req = Request::new(Method::Get, Url::parse(&mock_server.uri()).unwrap());
+        let governor = GovernorMiddleware::builder()
+            .per_minute(1)
+            .window(Quota::per_hour(NonZeroU32::new(1).unwrap()))
+            .build()?;
+        let client = Client::new().with(governor);
+        assert_eq!(client.send(req.clone()).await?.status(), 200);
+        let wait_res = client.send(req).await?;
+        assert_eq!(wait_res.status(), 429);
+        let retry_after: u64 = wait_res["Retry-After"].as_str().parse()?;
+        assert!(retry_after > 60);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn limits_all_requests_globally() -> surf::Result<()> {
+        let first_server = MockServer::start().await;
+        let second_server = MockServer::start().await;
+        let m = || {
+            Mock::given(method("GET"))
+                .respond_with(ResponseTemplate::new(200).set_body_string("Hello!".to_string()))
+        };
+        let _first_guard = first_server.register_as_scoped(m().expect(1)).await;
+        let _second_guard = second_server.register_as_scoped(m().expect(0)).await;
+        let first = Request::new(Method::Get, Url::parse(&first_server.uri()).unwrap());
+        let second = Request::new(Method::Get, Url::parse(&second_server.uri()).unwrap());
+        let governor = GovernorMiddleware::builder()
+            .per_second(1)
+            .global()
+            .build()?;
+        let client = Client::new().with(governor);
+        assert_eq!(client.send(first).await?.status(), 200);
+        assert_eq!(client.send(second).await?.status(), 429);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn limits_host_patterns_with_their_own_quota() -> surf::Result<()> {
+        let mock_server = MockServer::start().await;
+        let m = Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("Hello!".to_string()))
+            .expect(3);
+        let _mock_guard = mock_server.register_as_scoped(m).await;
+        let req = Request::new(Method::Get, Url::parse(&mock_server.uri()).unwrap());
+        let governor = GovernorMiddleware::builder()
+            .per_second(1)
+            .host_quota("*.0.1", Quota::per_second(NonZeroU32::new(2).unwrap()))
+            .host_quota("127.*.0.1", Quota::per_second(NonZeroU32::new(3).unwrap()))
+            .build()?;
+        let client = Client::new().with(governor);
+        for _ in 0..3 {
+            assert_eq!(client.send(req.clone()).await?.status(), 200);
+        }
+        assert_eq!(client.send(req).await?.status(), 429);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn limits_host_groups_together() -> surf::Result<()> {
+        let first_server = MockServer::start().await;
+        let second_server = MockServer::start().await;
+        let m = || {
+            Mock::given(method("GET"))
+                .respond_with(ResponseTemplate::new(200).set_body_string("Hello!".to_string()))
+        };
+        let _first_guard = first_server.register_as_scoped(m().expect(1)).await;
+        let _second_guard = second_server.register_as_scoped(m().expect(1)).await;
+        let first = Request::new(Method::Get, Url::parse(&first_server.uri()).unwrap());
+        let second = Request::new(Method::Get, Url::parse(&second_server.uri()).unwrap());
+        let governor = GovernorMiddleware::builder()
+            .per_second(10)
+            .host_group(
+                "mocks",
+                [first_server.uri(), second_server.uri()],
+                Quota::per_second(NonZeroU32::new(2).unwrap()),
+            )
+            .build()?;
+        let client = Client::new().with(governor.clone());
+        assert_eq!(client.send(first.clone()).await?.status(), 200);
+        assert_eq!(client.send(second.clone()).await?.status(), 200);
+        assert_eq!(client.send(first.clone()).await?.status(), 429);
+        assert_eq!(client.send(second).await?.status(), 429);
+        assert!(governor.remove_host_group("mocks"));
+        assert!(!governor.remove_host_group("mocks"));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn enforces_layers_together() -> surf::Result<()> {
+        let mock_server = MockServer::start().await;
+        let m = Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("Hello!".to_string()))
+            .expect(1);
+        let _mock_guard = mock_server.register_as_scoped(m).await;
+        let m = Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("Hello!".to_string()))
+            .expect(2);
+        let _get_guard = mock_server.register_as_scoped(m).await;
+        let url = format!("{}/v1/orders/42", &mock_server.uri());
+        let order = Request::new(Method::Post, Url::parse(&url).unwrap());
+        let lookup = Request::new(Method::Get, Url::parse(&url).unwrap());
+        let quota = |count| Quota::per_second(NonZeroU32::new(count).unwrap());
+        let governor = GovernorMiddleware::builder()
+            .per_second(3)
+            .layer(Layer::global(quota(10)))
+            .layer(Layer::route(Method::Post, "/v1/orders", quota(1)))
+            .build()?;
+        let client = Client::new().with(governor);
+        assert_eq!(client.send(order.clone()).await?.status(), 200);
+        assert_eq!(client.send(order).await?.status(), 429);
+        assert_eq!(client.send(lookup.clone()).await?.status(), 200);
+        assert_eq!(client.send(lookup.clone()).await?.status(), 200);
+        assert_eq!(client.send(lookup).await?.status(), 429);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn backs_off_after_upstream_retry_after() -> surf::Result<()> {
+        let mock_server = MockServer::start().await;
+        let m = Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "120"))
+            .expect(1);
+        let _mock_guard = mock_server.register_as_scoped(m).await;
+        let req = Request::new(Method::Get, Url::parse(&mock_server.uri()).unwrap());
+        let client = Client::new().with(GovernorMiddleware::per_second(100)?.honor_retry_after());
+        assert_eq!(client.send(req.clone()).await?.status(), 429);
+        let wait_res = client.send(req).await?;
+        assert_eq!(wait_res.status(), 429);
+        let retry_after: u64 = wait_res["Retry-After"].as_str().parse()?;
+        assert!((110..=120).contains(&retry_after));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn backs_off_until_upstream_retry_after_date() -> surf::Result<()> {
+        let mock_server = MockServer::start().await;
+        let at = RetryAfter::new_at(SystemTime::now() + Duration::from_secs(300));
+        let m = Mock::given(method("GET"))
+            .respond_with(
+                ResponseTemplate::new(503).insert_header("Retry-After", at.value().as_str()),
+            )
+            .expect(1);
+        let _mock_guard = mock_server.register_as_scoped(m).await;
+        let req = Request::new(Method::Get, Url::parse(&mock_server.uri()).unwrap());
+        let client = Client::new().with(GovernorMiddleware::per_second(100)?.honor_retry_after());
+        assert_eq!(client.send(req.clone()).await?.status(), 503);
+        let wait_res = client.send(req).await?;
+        assert_eq!(wait_res.status(), 429);
+        let retry_after: u64 = wait_res["Retry-After"].as_str().parse()?;
+        assert!((290..=300).contains(&retry_after));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn caps_huge_upstream_retry_after() -> surf::Result<()> {
+        let date = "Fri, 31 Dec 9999 23:59:59 GMT";
+        for retry_after in ["99999999999", "18446744073709551615", date] {
+            let mock_server = MockServer::start().await;
+            let m = Mock::given(method("GET"))
+                .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", retry_after))
+                .expect(1);
+            let _mock_guard = mock_server.register_as_scoped(m).await;
+            let req = Request::new(Method::Get, Url::parse(&mock_server.uri()).unwrap());
+            let client =
+                Client::new().with(GovernorMiddleware::per_second(100)?.honor_retry_after());
+            assert_eq!(client.send(req.clone()).await?.status(), 429);
+            let wait_res = client.send(req).await?;
+            assert_eq!(wait_res.status(), 429);
+            let retry_after: u64 = wait_res["Retry-After"].as_str().parse()?;
+            assert!(retry_after <= 60 * 60 * 24 * 365);
+        }
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn caps_huge_reported_resets() -> surf::Result<()> {
+        for reset in ["99999999999", "18446744073709551615"] {
+            let mock_server = MockServer::start().await;
+            let m = Mock::given(method("GET"))
+                .respond_with(
+                    ResponseTemplate::new(200)
+                        .insert_header("X-RateLimit-Remaining", "0")
+                        .insert_header("X-RateLimit-Reset", reset),
+                )
+                .expect(1);
+            let _mock_guard = mock_server.register_as_scoped(m).await;
+            let req = Request::new(Method::Get, Url::parse(&mock_server.uri()).unwrap());
+            let governor = GovernorMiddleware::per_second(100)?.follow_rate_limit_headers();
+            let client = Client::new().with(governor);
+            assert_eq!(client.send(req.clone()).await?.status(), 200);
+            let wait_res = client.send(req).await?;
+            assert_eq!(wait_res.status(), 429);
+            let retry_after: u64 = wait_res["Retry-After"].as_str().parse()?;
+            assert!(retry_after <= 60 * 60 * 24 * 365);
+        }
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn follows_reported_remaining_requests() -> surf::Result<()> {
+        let mock_server = MockServer::start().await;
+        let reported = Mock::given(method("GET"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("X-RateLimit-Remaining", "2")
+                    .insert_header("X-RateLimit-Reset", "60"),
+            )
+            .up_to_n_times(1)
+            .expect(1);
+        let unreported = Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(2);
+        let _reported_guard = mock_server.register_as_scoped(reported).await;
+        let _unreported_guard = mock_server.register_as_scoped(unreported).await;
+        let req = Request::new(Method::Get, Url::parse(&mock_server.uri()).unwrap());
+        let client =
+            Client::new().with(GovernorMiddleware::per_second(100)?.follow_rate_limit_headers());
+        for _ in 0..3 {
+            assert_eq!(client.send(req.clone()).await?.status(), 200);
+        }
+        let wait_res = client.send(req).await?;
+        assert_eq!(wait_res.status(), 429);
+        let retry_after: u64 = wait_res["Retry-After"].as_str().parse()?;
+        assert!((50..=60).contains(&retry_after));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn does_not_count_free_requests_against_reported_remaining() -> surf::Result<()> {
+        let mock_server = MockServer::start().await;
+        let reported = Mock::given(method("GET"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("X-RateLimit-Remaining", "1")
+                    .insert_header("X-RateLimit-Reset", "60"),
+            )
+            .up_to_n_times(1)
+            .expect(1);
+        let unreported = Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(3);
+        let _reported_guard = mock_server.register_as_scoped(reported).await;
+        let _unreported_guard = mock_server.register_as_scoped(unreported).await;
+        let req = Request::new(Method::Get, Url::parse(&mock_server.uri()).unwrap());
+        let client =
+            Client::new().with(GovernorMiddleware::per_second(100)?.follow_rate_limit_headers());
+        assert_eq!(client.send(req.clone()).await?.status(), 200);
+        for _ in 0..2 {
+            let mut free = Request::new(Method::Get, req.url().clone());
+            free.set_ext(Cost::new(0));
+            assert_eq!(client.send(free).await?.status(), 200);
+        }
+        assert_eq!(client.send(req.clone()).await?.status(), 200);
+        assert_eq!(client.send(req).await?.status(), 429);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn follows_ietf_rate_limit_headers() -> surf::Result<()> {
+        let mock_server = MockServer::start().await;
+        let m = Mock::given(method("GET"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("RateLimit", "\"default\";r=0")
+                    .insert_header("RateLimit-Policy", "\"default\";q=100;w=30"),
+            )
+            .expect(1);
+        let _mock_guard = mock_server.register_as_scoped(m).await;
+        let req = Request::new(Method::Get, Url::parse(&mock_server.uri()).unwrap());
+        let client =
+            Client::new().with(GovernorMiddleware::per_second(100)?.follow_rate_limit_headers());
+        assert_eq!(client.send(req.clone()).await?.status(), 200);
+        let wait_res = client.send(req).await?;
+        assert_eq!(wait_res.status(), 429);
+        let retry_after: u64 = wait_res["Retry-After"].as_str().parse()?;
+        assert!((20..=30).contains(&retry_after));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn slows_down_after_congestion() -> surf::Result<()> {
+        let mock_server = MockServer::start().await;
+        let congested = Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(429))
+            .up_to_n_times(1)
+            .expect(1);
+        let ok = Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1);
+        let _congested_guard = mock_server.register_as_scoped(congested).await;
+        let _ok_guard = mock_server.register_as_scoped(ok).await;
+        let req = Request::new(Method::Get, Url::parse(&mock_server.uri()).unwrap());
+        let quota = |count| Quota::per_second(NonZeroU32::new(count).unwrap());
+        let governor = GovernorMiddleware::per_second(10)?
+            .with_burst(10)?
+            .with_aimd(Aimd::new(quota(1), quota(20)))?;
+        let client = Client::new().with(governor);
+        assert_eq!(client.send(req.clone()).await?.status(), 429);
+        let wait_res = client.send(req.clone()).await?;
+        assert_eq!(wait_res.status(), 429);
+        assert_eq!(wait_res["Retry-After"], "1");
+        async_std::task::sleep(Duration::from_millis(250)).await;
+        assert_eq!(client.send(req).await?.status(), 200);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn slows_down_after_timeout() -> surf::Result<()> {
+        let mock_server = MockServer::start().await;
+        let slow = Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(3)))
+            .up_to_n_times(1)
+            .expect(1);
+        let _slow_guard = mock_server.register_as_scoped(slow).await;
+        let req = Request::new(Method::Get, Url::parse(&mock_server.uri()).unwrap());
+        let quota = |count| Quota::per_second(NonZeroU32::new(count).unwrap());
+        let governor =
+            GovernorMiddleware::per_second(10)?.with_aimd(Aimd::new(quota(1), quota(20)))?;
+        let client: Client = Config::new()
+            .set_timeout(Some(Duration::from_millis(200)))
+            .try_into()?;
+        let client = client.with(governor);
+        client.send(req.clone()).await.unwrap_err();
+        let wait_res = client.send(req).await?;
+        assert_eq!(wait_res.status(), 429);
+        assert_eq!(wait_res["x-governor-limited"], "local");
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn waits_for_rate_cut_while_waiting() -> surf::Result<()> {
+        let mock_server = MockServer::start().await;
+        let congested = Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(429).set_delay(Duration::from_millis(200)))
+            .up_to_n_times(1)
+            .expect(1);
+        let ok = Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1);
+        let _congested_guard = mock_server.register_as_scoped(congested).await;
+        let _ok_guard = mock_server.register_as_scoped(ok).await;
+        let req = Request::new(Method::Get, Url::parse(&mock_server.uri()).unwrap());
+        let quota = |count| Quota::per_second(NonZeroU32::new(count).unwrap());
+        let governor = GovernorMiddleware::per_second(2)?
+            .with_burst(1)?
+            .with_aimd(Aimd::new(quota(1), quota(2)))?
+            .on_limit(OnLimit::Wait);
+        let client = Client::new().with(governor);
+        let start = Instant::now();
+        let congested = async_std::task::spawn({
+            let (client, req) = (client.clone(), req.clone());
+            async move { client.send(req).await }
+        });
+        async_std::task::sleep(Duration::from_millis(50)).await;
+        assert_eq!(client.send(req).await?.status(), 200);
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        assert_eq!(congested.await?.status(), 429);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn adapts_burst_set_after_aimd() -> surf::Result<()> {
+        let mock_server = MockServer::start().await;
+        let m = Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(2);
+        let _mock_guard = mock_server.register_as_scoped(m).await;
+        let req = Request::new(Method::Get, Url::parse(&mock_server.uri()).unwrap());
+        let quota = |count| Quota::per_second(NonZeroU32::new(count).unwrap());
+        let governor = GovernorMiddleware::per_second(10)?
+            .with_aimd(Aimd::new(quota(1), quota(50)))?
+            .with_burst(2)?;
+        let client = Client::new().with(governor);
+        assert_eq!(client.send(req.clone()).await?.status(), 200);
+        assert_eq!(client.send(req.clone()).await?.status(), 200);
+        assert_eq!(client.send(req).await?.status(), 429);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn keeps_burst_while_speeding_up() -> surf::Result<()> {
+        let mock_server = MockServer::start().await;
+        let m = Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(5);
+        let _mock_guard = mock_server.register_as_scoped(m).await;
+        let req = Request::new(Method::Get, Url::parse(&mock_server.uri()).unwrap());
+        let quota = |count| Quota::per_second(NonZeroU32::new(count).unwrap());
+        let governor =
+            GovernorMiddleware::per_second(10)?.with_aimd(Aimd::new(quota(1), quota(50)))?;
+        let client = Client::new().with(governor);
+        for _ in 0..5 {
+            assert_eq!(client.send(req.clone()).await?.status(), 200);
+        }
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn limits_requests_in_flight() -> surf::Result<()> {
+        let mock_server = MockServer::start().await;
+        let m = Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_millis(300)))
+            .expect(2);
+        let _mock_guard = mock_server.register_as_scoped(m).await;
+        let req = Request::new(Method::Get, Url::parse(&mock_server.uri()).unwrap());
+        let client =
+            Client::new().with(GovernorMiddleware::per_second(100)?.with_max_in_flight(1)?);
+        let in_flight = async_std::task::spawn({
+            let (client, req) = (client.clone(), req.clone());
+            async move { client.send(req).await }
+        });
+        async_std::task::sleep(Duration::from_millis(100)).await;
+        let res = client.send(req.clone()).await?;
+        assert_eq!(res.status(), 429);
+        let limited = res.ext::<RateLimited>().unwrap();
+        assert_eq!(limited.wait(), Duration::from_millis(100));
+        assert_eq!(in_flight.await?.status(), 200);
+        assert_eq!(client.send(req).await?.status(), 200);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn waits_for_requests_in_flight() -> surf::Result<()> {
+        let mock_server = MockServer::start().await;
+        let m = Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_millis(200)))
+            .expect(2);
+        let _mock_guard = mock_server.register_as_scoped(m).await;
+        let req = Request::new(Method::Get, Url::parse(&mock_server.uri()).unwrap());
+        let governor = GovernorMiddleware::per_second(100)?
+            .with_max_in_flight(1)?
+            .on_limit(OnLimit::Wait);
+        let client = Client::new().with(governor);
+        let start = Instant::now();
+        let first = async_std::task::spawn({
+            let (client, req) = (client.clone(), req.clone());
+            async move { client.send(req).await }
+        });
+        assert_eq!(client.send(req).await?.status(), 200);
+        assert_eq!(first.await?.status(), 200);
+        assert!(start.elapsed() >= Duration::from_millis(400));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn charges_request_costs() -> surf::Result<()> {
+        let mock_server = MockServer::start().await;
+        let m = Mock::given(any()).respond_with(ResponseTemplate::new(200));
+        let _mock_guard = mock_server.register_as_scoped(m).await;
+        let url = Url::parse(&mock_server.uri()).unwrap();
+        let governor = GovernorMiddleware::builder()
+            .per_second(1)
+            .burst(10)
+            .cost(|_| 4)
+            .route_cost(Method::Post, "/", 3)
+            .build()?;
+        let client = Client::new().with(governor);
+        assert_eq!(client.get(&url).await?.status(), 200);
+        assert_eq!(client.post(&url).await?.status(), 200);
+        let mut free = Request::new(Method::Get, url.clone());
+        free.set_ext(Cost::new(0));
+        assert_eq!(client.send(free).await?.status(), 200);
+        let mut cheap = Request::new(Method::Get, url.clone());
+        cheap.set_ext(Cost::new(3));
+        assert_eq!(client.send(cheap).await?.status(), 200);
+        assert_eq!(client.post(&url).await?.status(), 429);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn rejects_requests_exceeding_capacity() -> surf::Result<()> {
+        let mock_server = MockServer::start().await;
+        let m = Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(0);
+        let _mock_guard = mock_server.register_as_scoped(m).await;
+        let mut req = Request::new(Method::Get, Url::parse(&mock_server.uri()).unwrap());
+        req.set_ext(Cost::new(11));
+        let governor = GovernorMiddleware::per_second(10)?.on_limit(OnLimit::Wait);
+        let client = Client::new().with(governor);
+        let err = client.send(req).await.unwrap_err();
+        assert_eq!(err.status(), 400);
+        assert_eq!(
+            err.downcast_ref::<ExceedsCapacityError>(),
+            Some(&ExceedsCapacityError::new(11, 10))
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn returns_rate_limited_error() -> surf::Result<()> {
+        let mock_server = MockServer::start().await;
+        let m = Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1);
+        let _mock_guard = mock_server.register_as_scoped(m).await;
+        let url = Url::parse(&mock_server.uri()).unwrap();
+        let req = Request::new(Method::Get, url.clone());
+        let client =
+            Client::new().with(GovernorMiddleware::per_second(1)?.on_limit(OnLimit::Error));
+        assert_eq!(client.send(req.clone()).await?.status(), 200);
+        let err = client.send(req).await.unwrap_err();
+        assert_eq!(err.status(), 429);
+        let limited = err.downcast_ref::<RateLimited>().unwrap();
+        assert_eq!(limited.key(), Some(&url.origin().ascii_serialization()));
+        assert!(limited.wait() <= Duration::from_secs(1));
+        assert_eq!(
+            limited.quota(),
+            Some(Quota::per_second(NonZeroU32::new(1).unwrap()))
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn marks_local_rejections() -> surf::Result<()> {
+        let mock_server = MockServer::start().await;
+        let m = Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(429))
+            .expect(2);
+        let _mock_guard = mock_server.register_as_scoped(m).await;
+        let req = Request::new(Method::Get, Url::parse(&mock_server.uri()).unwrap());
+        let client = Client::new().with(GovernorMiddleware::per_second(1)?);
+        let upstream = client.send(req.clone()).await?;
+        assert_eq!(upstream.status(), 429);
+        assert!(upstream.header("X-Governor-Limited").is_none());
+        assert!(upstream.ext::<RateLimited>().is_none());
+        let local = client.send(req.clone()).await?;
+        assert_eq!(local.status(), 429);
+        assert_eq!(local["X-Governor-Limited"], "local");
+        assert!(local.ext::<RateLimited>().unwrap().wait() <= Duration::from_secs(1));
+
+        let governor = GovernorMiddleware::builder()
+            .per_second(1)
+            .marker_header("X-Throttled", "client".parse()?)
+            .build()?;
+        let client = Client::new().with(governor);
+        client.send(req.clone()).await?;
+        let local = client.send(req).await?;
+        assert_eq!(local["X-Throttled"], "client");
+        assert!(local.header("X-Governor-Limited").is_none());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn reports_precise_rate_limit_headers() -> surf::Result<()> {
+        let mock_server = MockServer::start().await;
+        let m = Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(2);
+        let _mock_guard = mock_server.register_as_scoped(m).await;
+        let req = Request::new(Method::Get, Url::parse(&mock_server.uri()).unwrap());
+        let governor = GovernorMiddleware::builder()
+            .per_second(2)
+            .burst(4)
+            .rate_limit_headers()
+            .build()?;
+        let client = Client::new().with(governor);
+        for _ in 0..2 {
+            assert_eq!(client.send(req.clone()).await?.status(), 200);
+        }
+        let mut costly = req.clone();
+        costly.set_ext(Cost::new(3));
+        let wait_res = client.send(costly).await?;
+        assert_eq!(wait_res.status(), 429);
+        assert_eq!(wait_res["Retry-After"], "1");
+        let retry_after_ms: u64 = wait_res["Retry-After-Ms"].as_str().parse()?;
+        assert!((1..=500).contains(&retry_after_ms));
+        assert_eq!(wait_res["RateLimit-Limit"], "4");
+        assert_eq!(wait_res["RateLimit-Remaining"], "2");
+        assert_eq!(wait_res["RateLimit-Reset"], "1");
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn customises_rejection_responses() -> surf::Result<()> {
+        let mock_server = MockServer::start().await;
+        let m = Mock::given(method("GET")).respond_with(ResponseTemplate::new(200));
+        let _mock_guard = mock_server.register_as_scoped(m).await;
+        let url = Url::parse(&mock_server.uri()).unwrap();
+        let req = Request::new(Method::Get, url.clone());
+        let rejection = RejectionResponse::new(StatusCode::ServiceUnavailable)
+            .body(RejectionBody::Json)
+            .header("Cache-Control", "no-store".parse()?);
+        let governor = GovernorMiddleware::per_hour(1)?.with_rejection_response(rejection);
+        let client = Client::new().with(governor);
+        client.send(req.clone()).await?;
+        let mut res = client.send(req.clone()).await?;
+        assert_eq!(res.status(), 503);
+        assert_eq!(res["Cache-Control"], "no-store");
+        assert_eq!(res["X-Governor-Limited"], "local");
+        assert!(res.header("Retry-After").is_some());
+        let body = res.body_string().await?;
+        let key = url.origin().ascii_serialization();
+        assert!(body.starts_with(&format!(r#"{{"key":"{key}","retry_after_ms":"#)));
+
+        let governor = GovernorMiddleware::per_hour(1)?.with_rejection_handler(|req, limited| {
+            let mut res = Response::new(StatusCode::TooManyRequests);
+            res.set_body(format!(
+                "{} limited: {}",
+                req.method(),
+                limited.key().unwrap()
+            ));
+            res
+        });
+        let client = Client::new().with(governor);
+        client.send(req.clone()).await?;
+        let mut res = client.send(req.clone()).await?;
+        assert_eq!(res.status(), 429);
+        assert_eq!(res["X-Governor-Limited"], "local");
+        assert!(res.ext::<RateLimited>().is_some());
+        assert_eq!(res.body_string().await?, format!("GET limited: {key}"));
+        let retry_after: u64 = res["Retry-After"].as_str().parse()?;
+        assert!((3500..=3600).contains(&retry_after));
+
+        let governor = GovernorMiddleware::per_hour(1)?.with_rejection_handler(|_, _| {
+            let mut res = Response::new(StatusCode::TooManyRequests);
+            res.insert_header("Retry-After", "7");
+            res
+        });
+        let client = Client::new().with(governor);
+        client.send(req.clone()).await?;
+        let res = client.send(req).await?;
+        assert_eq!(res["Retry-After"], "7");
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn passes_through_requests_without_key() -> surf::Result<()> {
+        let mock_server = MockServer::start().await;
+        let m = Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("Hello!".to_string()))
+            .expect(2);
+        let _mock_guard = mock_server.register_as_scoped(m).await;
+        let url = format!("{}/", &mock_server.uri());
+        let req = Request::new(Method::Get, Url::parse(&url).unwrap());
+        let governor = GovernorMiddleware::builder()
+            .per_second(1)
+            .key_extractor(HeaderKey::new("X-Api-Key"))
+            .on_missing_key(MissingKey::PassThrough)
+            .build()?;
+        let client = Client::new().with(governor);
+        assert_eq!(client.send(req.clone()).await?.status(), 200);
+        assert_eq!(client.send(req).await?.status(), 200);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn limits_requests_without_key_under_fallback() -> surf::Result<()> {
+        let mock_server = MockServer::start().await;
+        let m = Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("Hello!".to_string()))
+            .expect(1);
+        let _mock_guard = mock_server.register_as_scoped(m).await;
+        let url = format!("{}/", &mock_server.uri());
+        let req = Request::new(Method::Get, Url::parse(&url).unwrap());
+        let governor = GovernorMiddleware::builder()
+            .per_second(1)
+            .key_extractor(HeaderKey::new("X-Api-Key"))
+            .on_missing_key(MissingKey::Fallback("anonymous".to_string()))
+            .build()?;
+        let client = Client::new().with(governor);
+        assert_eq!(client.send(req.clone()).await?.status(), 200);
+        assert_eq!(client.send(req).await?.status(), 429);
+        Ok(())
+    }
+
+    /// Answers every request with a 200 response, for requests no server can receive.
+    #[derive(Debug)]
+    struct Answer;
+
+    #[surf::utils::async_trait]
+    impl surf::middleware::Middleware for Answer {
+        async fn handle(
+            &self,
+            _req: Request,
+            _client: Client,
+            _next: surf::middleware::Next<'_>,
+        ) -> surf::Result<surf::Response> {
+            Ok(Response::new(StatusCode::Ok).into())
+        }
+    }
+
+    #[async_std::test]
+    async fn passes_through_hostless_requests() -> surf::Result<()> {
+        let client = Client::new()
+            .with(GovernorMiddleware::per_second(1)?)
+            .with(Answer);
+        for url in ["file:///x", "data:text/plain,x", "custom:x"] {
+            let req = Request::new(Method::Get, Url::parse(url).unwrap());
+            assert_eq!(client.send(req.clone()).await?.status(), 200);
+            assert_eq!(client.send(req).await?.status(), 200);
+        }
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn limits_hostless_requests_under_fallback() -> surf::Result<()> {
+        let governor = GovernorMiddleware::per_second(1)?
+            .on_missing_key(MissingKey::Fallback("local".to_owned()));
+        let client = Client::new().with(governor).with(Answer);
+        let req = Request::new(Method::Get, Url::parse("file:///x").unwrap());
+        assert_eq!(client.send(req).await?.status(), 200);
+        let req = Request::new(Method::Get, Url::parse("data:text/plain,x").unwrap());
+        assert_eq!(client.send(req).await?.status(), 429);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn rejects_hostless_requests() -> surf::Result<()> {
+        let req = Request::new(Method::Get, Url::parse("file:///x").unwrap());
+        let client = Client::new()
+            .with(GovernorMiddleware::per_second(1)?.on_missing_key(MissingKey::Error));
+        let err = client.send(req).await.unwrap_err();
+        let missing = err.downcast_ref::<MissingKeyError>().unwrap();
+        assert_eq!(missing.url().as_str(), "file:///x");
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn waits_for_limiter() -> surf::Result<()> {
+        let mock_server = MockServer::start().await;
+        let m = Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("Hello!".to_string()))
+            .expect(2);
+        let _mock_guard = mock_server.register_as_scoped(m).await;
+        let url = format!("{}/", &mock_server.uri());
+        let req = Request::new(Method::Get, Url::parse(&url).unwrap());
+        let client = Client::new().with(
+            GovernorMiddleware::with_period(Duration::from_millis(200))
+                .unwrap()
+                .on_limit(OnLimit::Wait),
+        );
+        let start = Instant::now();
+        let first_res = client.send(req.clone()).await?;
+        assert_eq!(first_res.status(), 200);
+        let second_res = client.send(req).await?;
+        assert_eq!(second_res.status(), 200);
+        assert!(start.elapsed() >= Duration::from_millis(150));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn adds_jitter_to_retry_after() -> surf::Result<()> {
+        let mock_server = MockServer::start().await;
+        let m = Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("Hello!".to_string()))
+            .expect(1);
+        let _mock_guard = mock_server.register_as_scoped(m).await;
+        let url = format!("{}/", &mock_server.uri());
+        let req = Request::new(Method::Get, Url::parse(&url).unwrap());
+        let client = Client::new().with(GovernorMiddleware::per_hour(1)?.with_jitter(Jitter::new(
+            Duration::from_secs(3600),
+            Duration::from_secs(60),
+        )));
+        let good_res = client.send(req.clone()).await?;
+        assert_eq!(good_res.status(), 200);
+        let wait_res = client.send(req).await?;
+        assert_eq!(wait_res.status(), 429);
+        let retry_after: u64 = wait_res["Retry-After"].as_str().parse()?;
+        assert!((7199..=7260).contains(&retry_after));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn measures_time_with_injected_clock() -> surf::Result<()> {
+        let mock_server = MockServer::start().await;
+        let m = Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(3);
+        let _mock_guard = mock_server.register_as_scoped(m).await;
+        let url = Url::parse(&mock_server.uri()).unwrap();
+        let clock = FakeRelativeClock::default();
+        let governor = GovernorMiddleware::builder()
+            .per_second(2)
+            .rate_limit_headers()
+            .clock(clock.clone())
+            .build()?;
+        let client = Client::new().with(governor);
+        let retry_after_ms = |res: &surf::Response| res["retry-after-ms"].as_str().to_owned();
+        assert_eq!(client.get(&url).await?.status(), 200);
+        assert_eq!(client.get(&url).await?.status(), 200);
+        let res = client.get(&url).await?;
+        assert_eq!(res.status(), 429);
+        assert_eq!(retry_after_ms(&res), "500");
+        clock.advance(Duration::from_millis(499));
+        let res = client.get(&url).await?;
+        assert_eq!(res.status(), 429);
+        assert_eq!(retry_after_ms(&res), "1");
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(client.get(&url).await?.status(), 200);
+        assert_eq!(client.get(&url).await?.status(), 429);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn shares_limiters_between_clients() -> surf::Result<()> {
+        let mock_server = MockServer::start().await;
+        let m = Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(2);
+        let _mock_guard = mock_server.register_as_scoped(m).await;
+        let url = Url::parse(&mock_server.uri()).unwrap();
+        let registry = GovernorRegistry::new();
+        let governor = || GovernorMiddleware::per_minute(2).unwrap();
+        let first = Client::new().with(registry.get_or_insert_with("upstream", governor));
+        let second = Client::new().with(registry.get_or_insert_with("upstream", governor));
+        assert_eq!(first.get(&url).await?.status(), 200);
+        assert_eq!(second.get(&url).await?.status(), 200);
+        assert_eq!(first.get(&url).await?.status(), 429);
+        assert_eq!(second.get(&url).await?.status(), 429);
+        assert!(registry.remove("upstream").is_some());
+        assert!(registry.get("upstream").is_none());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn takes_permits_outside_requests() -> surf::Result<()> {
+        let mock_server = MockServer::start().await;
+        let m = Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1);
+        let _mock_guard = mock_server.register_as_scoped(m).await;
+        let url = Url::parse(&mock_server.uri()).unwrap();
+        let key = url.origin().ascii_serialization();
+        let clock = FakeRelativeClock::default();
+        let governor = GovernorMiddleware::per_second(2)?.with_clock(clock.clone());
+        let client = Client::new().with(governor.clone());
+        assert_eq!(client.get(&url).await?.status(), 200);
+        assert_eq!(governor.check(&key), Ok(()));
+        assert_eq!(client.get(&url).await?.status(), 429);
+        let limited = governor.check(&key).unwrap_err();
+        assert_eq!(limited.key(), Some(&key));
+        assert_eq!(limited.wait(), Duration::from_millis(500));
+        assert_eq!(
+            governor.acquire_n(&key, 3).await,
+            Err(ExceedsCapacityError::new(3, 2))
+        );
+        clock.advance(Duration::from_millis(500));
+        governor.acquire(&key).await;
+        assert!(governor.check(&key).is_err());
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(governor.check_n(&key, 2), Ok(Ok(())));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn takes_permits_for_urls() -> surf::Result<()> {
+        let mock_server = MockServer::start().await;
+        let m = Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1);
+        let _mock_guard = mock_server.register_as_scoped(m).await;
+        let url = Url::parse(&mock_server.uri()).unwrap();
+        let clock = FakeRelativeClock::default();
+        let governor = GovernorMiddleware::per_second(100)?.with_clock(clock.clone());
+        let quota = Quota::per_second(NonZeroU32::new(2).unwrap());
+        governor.set_host_quota(url.host_str().unwrap(), quota);
+        let client = Client::new().with(governor.clone());
+        assert_eq!(client.get(&url).await?.status(), 200);
+        let socket = url.join("/socket")?;
+        assert!(governor.check_url(&socket, 1)?.is_ok());
+        assert_eq!(client.get(&url).await?.status(), 429);
+        let limited = governor.check_url(&socket, 1)?.unwrap_err();
+        assert_eq!(limited.wait(), Duration::from_millis(500));
+        assert_eq!(limited.quota(), Some(quota));
+        let err = governor.acquire_url(&socket, 3).await.unwrap_err();
+        assert_eq!(err.status(), 400);
+        assert!(err.downcast_ref::<ExceedsCapacityError>().is_some());
+        clock.advance(Duration::from_millis(500));
+        governor.acquire_url(&socket, 1).await?;
+        assert!(governor.check_url(&socket, 1)?.is_err());
         Ok(())
     }
 }
@@ -0,0 +1,106 @@
+use std::cmp::Reverse;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Label {
+    Any,
+    Literal(String),
+}
+
+/// A host name in which `*` labels stand for any label, e.g. `api.*.example.com`.
+///
+/// A leading `*` matches one or more labels, so `*.example.com` matches both
+/// `a.example.com` and `a.b.example.com`, but not `example.com`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct HostPattern {
+    any_prefix: bool,
+    labels: Vec<Label>,
+}
+
+impl HostPattern {
+    pub(crate) fn is_pattern(host: &str) -> bool {
+        host.split('.').any(|label| label == "*")
+    }
+
+    pub(crate) fn new(pattern: &str) -> Self {
+        let pattern = pattern.to_ascii_lowercase();
+        let mut labels: Vec<Label> = pattern
+            .split('.')
+            .map(|label| match label {
+                "*" => Label::Any,
+                label => Label::Literal(label.to_owned()),
+            })
+            .collect();
+        let any_prefix = labels.first() == Some(&Label::Any);
+        if any_prefix {
+            labels.remove(0);
+        }
+        Self { any_prefix, labels }
+    }
+
+    pub(crate) fn matches(&self, host: &str) -> bool {
+        let host: Vec<&str> = host.split('.').collect();
+        let skipped = match host.len().checked_sub(self.labels.len()) {
+            Some(0) => !self.any_prefix,
+            Some(_) => self.any_prefix,
+            None => false,
+        };
+        skipped
+            && host[host.len() - self.labels.len()..]
+                .iter()
+                .zip(&self.labels)
+                .all(|(host, label)| match label {
+                    Label::Any => !host.is_empty(),
+                    Label::Literal(label) => host.eq_ignore_ascii_case(label),
+                })
+    }
+
+    /// Orders patterns so that more specific ones come first: the ones with more
+    /// literal labels, then the ones matching fewer labels.
+    pub(crate) fn specificity(&self) -> impl Ord {
+        let literals = self
+            .labels
+            .iter()
+            .filter(|label| matches!(label, Label::Literal(_)))
+            .count();
+        Reverse((literals, self.labels.len(), !self.any_prefix))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HostPattern;
+
+    #[test]
+    fn matches_hosts() {
+        let suffix = HostPattern::new("*.s3.amazonaws.com");
+        assert!(suffix.matches("bucket.s3.amazonaws.com"));
+        assert!(suffix.matches("a.bucket.s3.amazonaws.com"));
+        assert!(!suffix.matches("s3.amazonaws.com"));
+        assert!(!suffix.matches("bucket.s3.amazonaws.org"));
+        let infix = HostPattern::new("api.*.Example.com");
+        assert!(infix.matches("api.eu.example.com"));
+        assert!(!infix.matches("api.eu.west.example.com"));
+        assert!(!infix.matches("api.example.com"));
+        assert!(!infix.matches("www.eu.example.com"));
+    }
+
+    #[test]
+    fn orders_by_specificity() {
+        let mut patterns = vec![
+            HostPattern::new("*.com"),
+            HostPattern::new("*.example.com"),
+            HostPattern::new("api.*.example.com"),
+            HostPattern::new("*.*.example.com"),
+        ];
+        patterns.sort_by_key(HostPattern::specificity);
+        assert_eq!(
+            patterns,
+            vec![
+                HostPattern::new("api.*.example.com"),
+                HostPattern::new("*.*.example.com"),
+                HostPattern::new("*.example.com"),
+                HostPattern::new("*.com"),
+            ]
+        );
+    }
+}
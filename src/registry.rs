@@ -0,0 +1,95 @@
+use crate::{GovernorMiddleware, RateLimitClock, RateLimitKey};
+use governor::clock::DefaultClock;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+/// Governors kept by name, so that clients built in different places draw from the same
+/// limiters.
+///
+/// A governor taken from the registry is a clone sharing its limiters with every other
+/// clone, see [`GovernorMiddleware`]. Clones of the registry share its governors.
+///
+/// # Example
+/// This constructs two clients with different timeouts that share one budget
+/// ```no_run
+/// use surf_governor::{GovernorMiddleware, GovernorRegistry};
+/// use surf::{Client, Config};
+///
+/// use std::time::Duration;
+///
+/// # fn main() -> surf::Result<()> {
+/// let registry = GovernorRegistry::new();
+/// let governor = || GovernorMiddleware::per_second(30).expect("30 is not zero");
+/// let fast: Client = Config::new()
+///     .set_timeout(Some(Duration::from_secs(5)))
+///     .try_into()?;
+/// let fast = fast.with(registry.get_or_insert_with("upstream", governor));
+/// let slow: Client = Config::new()
+///     .set_timeout(Some(Duration::from_secs(60)))
+///     .try_into()?;
+/// let slow = slow.with(registry.get_or_insert_with("upstream", governor));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct GovernorRegistry<K: RateLimitKey = String, C: RateLimitClock = DefaultClock> {
+    governors: Arc<Mutex<HashMap<String, GovernorMiddleware<K, C>>>>,
+}
+
+impl<K: RateLimitKey, C: RateLimitClock> Default for GovernorRegistry<K, C> {
+    fn default() -> Self {
+        Self {
+            governors: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl<K: RateLimitKey, C: RateLimitClock> GovernorRegistry<K, C> {
+    /// Constructs an empty registry.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the governor registered as `name`, if any.
+    #[must_use]
+    pub fn get(&self, name: &str) -> Option<GovernorMiddleware<K, C>> {
+        self.governors().get(name).cloned()
+    }
+
+    /// Returns the governor registered as `name`, registering the one returned by `f`
+    /// first if there is none.
+    pub fn get_or_insert_with<F>(&self, name: &str, f: F) -> GovernorMiddleware<K, C>
+    where
+        F: FnOnce() -> GovernorMiddleware<K, C>,
+    {
+        self.governors()
+            .entry(name.to_owned())
+            .or_insert_with(f)
+            .clone()
+    }
+
+    /// Registers `governor` as `name`, returning the governor it replaces, if any.
+    ///
+    /// Clients already holding the replaced governor keep drawing from its limiters.
+    pub fn insert(
+        &self,
+        name: impl Into<String>,
+        governor: GovernorMiddleware<K, C>,
+    ) -> Option<GovernorMiddleware<K, C>> {
+        self.governors().insert(name.into(), governor)
+    }
+
+    /// Unregisters the governor registered as `name`, returning it if there was one.
+    pub fn remove(&self, name: &str) -> Option<GovernorMiddleware<K, C>> {
+        self.governors().remove(name)
+    }
+
+    fn governors(&self) -> MutexGuard<'_, HashMap<String, GovernorMiddleware<K, C>>> {
+        self.governors
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
@@ -0,0 +1,228 @@
+use crate::{RateLimitKey, RateLimited};
+use http_types::{
+    headers::{HeaderName, HeaderValue, CONTENT_TYPE},
+    mime, Response, StatusCode,
+};
+use std::{any::Any, fmt, sync::Arc};
+use surf::Request;
+
+/// The body of the responses the middleware rejects requests with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RejectionBody {
+    /// No body.
+    Empty,
+    /// A plain text body.
+    Text(String),
+    /// A JSON object with the key of the request and how long it has to wait, such as
+    /// `{"key":"https://example.api","retry_after_ms":250}`.
+    Json,
+    /// An RFC 7807 `application/problem+json` object describing the rejection, with the
+    /// key of the request and how long it has to wait as extension members.
+    Problem,
+}
+
+impl Default for RejectionBody {
+    fn default() -> Self {
+        Self::Empty
+    }
+}
+
+/// The responses the middleware rejects requests with, when it is configured with
+/// [`OnLimit::Respond`](crate::OnLimit::Respond).
+///
+/// Whatever the configuration, those responses also carry a `Retry-After` header and the
+/// marker header and [`RateLimited`] extension of the middleware.
+///
+/// # Example
+/// This rejects requests with a 503 (service unavailable) response with a JSON body
+/// ```no_run
+/// use surf_governor::{GovernorMiddleware, RejectionBody, RejectionResponse};
+/// use surf::{Client, http::StatusCode};
+///
+/// # fn main() -> surf::Result<()> {
+/// let rejection = RejectionResponse::new(StatusCode::ServiceUnavailable)
+///     .body(RejectionBody::Json)
+///     .header("Cache-Control", "no-store".parse()?);
+/// let governor = GovernorMiddleware::per_second(30)?.with_rejection_response(rejection);
+/// let client = Client::new().with(governor);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RejectionResponse {
+    status: StatusCode,
+    body: RejectionBody,
+    headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+impl Default for RejectionResponse {
+    fn default() -> Self {
+        Self::new(StatusCode::TooManyRequests)
+    }
+}
+
+impl RejectionResponse {
+    /// Constructs responses with `status` and without a body.
+    #[must_use]
+    pub fn new(status: StatusCode) -> Self {
+        Self {
+            status,
+            body: RejectionBody::default(),
+            headers: Vec::new(),
+        }
+    }
+
+    /// Sets the body of the responses.
+    #[must_use]
+    pub fn body(mut self, body: RejectionBody) -> Self {
+        self.body = body;
+        self
+    }
+
+    /// Adds a header to the responses.
+    #[must_use]
+    pub fn header(mut self, name: impl Into<HeaderName>, value: HeaderValue) -> Self {
+        self.headers.push((name.into(), value));
+        self
+    }
+
+    /// Returns the status of the responses.
+    pub(crate) fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    /// Returns a response rejecting a request because it is `limited`.
+    pub(crate) fn respond<K: RateLimitKey>(&self, limited: &RateLimited<K>) -> Response {
+        let mut res = Response::new(self.status);
+        for (name, value) in &self.headers {
+            res.append_header(name, value.clone());
+        }
+        let key = match limited.key() {
+            Some(key) => json_string(&key_string(key)),
+            None => "null".to_owned(),
+        };
+        let wait = crate::millis(limited.wait());
+        match &self.body {
+            RejectionBody::Empty => {}
+            RejectionBody::Text(text) => res.set_body(text.as_str()),
+            RejectionBody::Json => {
+                res.set_body(format!(r#"{{"key":{key},"retry_after_ms":{wait}}}"#));
+                res.set_content_type(mime::JSON);
+            }
+            RejectionBody::Problem => {
+                let title = json_string(self.status.canonical_reason());
+                let detail = json_string(&limited.to_string());
+                let status = u16::from(self.status);
+                res.set_body(format!(
+                    r#"{{"type":"about:blank","title":{title},"status":{status},"detail":{detail},"key":{key},"retry_after_ms":{wait}}}"#
+                ));
+                res.insert_header(CONTENT_TYPE, "application/problem+json");
+            }
+        }
+        res
+    }
+}
+
+/// Returns `key` as is if it is a string, or else its debug representation.
+fn key_string<K: RateLimitKey>(key: &K) -> String {
+    let any: &dyn Any = key;
+    match any.downcast_ref::<String>() {
+        Some(key) => key.clone(),
+        None => format!("{key:?}"),
+    }
+}
+
+/// Returns `s` as a JSON string.
+fn json_string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c.is_control() => json.push_str(&format!("\\u{:04x}", u32::from(c))),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+type HandlerFn<K> = dyn Fn(&Request, &RateLimited<K>) -> Response + Send + Sync;
+
+/// A function building the responses the middleware rejects requests with.
+pub(crate) struct Handler<K>(Arc<HandlerFn<K>>);
+
+impl<K> Handler<K> {
+    pub(crate) fn new<F>(handler: F) -> Self
+    where
+        F: Fn(&Request, &RateLimited<K>) -> Response + Send + Sync + 'static,
+    {
+        Self(Arc::new(handler))
+    }
+
+    pub(crate) fn respond(&self, req: &Request, limited: &RateLimited<K>) -> Response {
+        (self.0)(req, limited)
+    }
+}
+
+impl<K> Clone for Handler<K> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<K> fmt::Debug for Handler<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Handler(Fn(&Request, &RateLimited) -> Response)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{json_string, RejectionBody, RejectionResponse};
+    use crate::RateLimited;
+    use http_types::StatusCode;
+    use std::time::Duration;
+
+    #[test]
+    fn escapes_json_strings() {
+        assert_eq!(json_string("plain"), r#""plain""#);
+        assert_eq!(json_string("\"a\\b\"\n"), r#""\"a\\b\"\n""#);
+        assert_eq!(json_string("\u{1}"), r#""\u0001""#);
+    }
+
+    #[async_std::test]
+    async fn builds_bodies() {
+        let limited =
+            RateLimited::new(Some("key".to_owned()), Duration::from_micros(249_400), None);
+        let body = |body| async {
+            let mut res = RejectionResponse::default().body(body).respond(&limited);
+            let content_type = res.content_type().map(|mime| mime.essence().to_owned());
+            (content_type, res.body_string().await.unwrap())
+        };
+        assert_eq!(body(RejectionBody::Empty).await.1, "");
+        assert_eq!(
+            body(RejectionBody::Text("slow down".to_owned())).await.1,
+            "slow down"
+        );
+        assert_eq!(
+            body(RejectionBody::Json).await,
+            (
+                Some("application/json".to_owned()),
+                r#"{"key":"key","retry_after_ms":250}"#.to_owned()
+            )
+        );
+        let (content_type, problem) = body(RejectionBody::Problem).await;
+        assert_eq!(content_type.as_deref(), Some("application/problem+json"));
+        assert!(problem
+            .starts_with(r#"{"type":"about:blank","title":"Too Many Requests","status":429,"#));
+        assert!(problem.ends_with(r#""key":"key","retry_after_ms":250}"#));
+        let res = RejectionResponse::new(StatusCode::ServiceUnavailable).respond(&limited);
+        assert_eq!(res.status(), StatusCode::ServiceUnavailable);
+    }
+}
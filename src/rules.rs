@@ -0,0 +1,446 @@
+use crate::{
+    pattern::HostPattern,
+    state::{peek, PeekableState},
+    Layer, RateLimitClock, RateLimitKey,
+};
+use governor::{
+    clock::Clock,
+    middleware::NoOpMiddleware,
+    state::{keyed::DefaultKeyedStateStore, InMemoryState, NotKeyed},
+    InsufficientCapacity, NotUntil, Quota, RateLimiter,
+};
+use std::{
+    collections::HashMap,
+    num::NonZeroU32,
+    sync::{Arc, Mutex, PoisonError},
+};
+use surf::{Request, Url};
+
+type NoOp<C> = NoOpMiddleware<<C as Clock>::Instant>;
+
+pub(crate) type KeyedLimiter<K, C> =
+    RateLimiter<K, PeekableState<DefaultKeyedStateStore<K>>, C, NoOp<C>>;
+pub(crate) type DirectLimiter<C> = RateLimiter<NotKeyed, PeekableState<InMemoryState>, C, NoOp<C>>;
+pub(crate) type Negative<C> = NotUntil<<C as Clock>::Instant>;
+
+/// A limiter with a bucket per key, or a single bucket shared by every request.
+#[derive(Debug)]
+pub(crate) enum Limiter<K: RateLimitKey, C: RateLimitClock> {
+    Keyed(Arc<KeyedLimiter<K, C>>),
+    Shared(Arc<DirectLimiter<C>>),
+}
+
+impl<K: RateLimitKey, C: RateLimitClock> Limiter<K, C> {
+    fn keyed(quota: Quota, clock: &C) -> Self {
+        let state = PeekableState::default();
+        Self::Keyed(Arc::new(RateLimiter::new(quota, state, clock)))
+    }
+
+    fn shared(quota: Quota, clock: &C) -> Self {
+        Self::Shared(direct(quota, clock))
+    }
+
+    /// Returns the bucket of this limiter for `key`, or `None` if it needs a key and
+    /// there is none.
+    pub(crate) fn target(self, key: Option<&K>) -> Option<Target<K, C>> {
+        match self {
+            Self::Keyed(limiter) => Some(Target::Keyed(limiter, key?.clone())),
+            Self::Shared(limiter) => Some(Target::Shared(limiter)),
+        }
+    }
+}
+
+impl<K: RateLimitKey, C: RateLimitClock> Clone for Limiter<K, C> {
+    fn clone(&self) -> Self {
+        match self {
+            Self::Keyed(limiter) => Self::Keyed(limiter.clone()),
+            Self::Shared(limiter) => Self::Shared(limiter.clone()),
+        }
+    }
+}
+
+pub(crate) fn direct<C: RateLimitClock>(quota: Quota, clock: &C) -> Arc<DirectLimiter<C>> {
+    let state = PeekableState::default();
+    Arc::new(RateLimiter::new(quota, state, clock))
+}
+
+/// The bucket a request is counted against.
+#[derive(Debug)]
+pub(crate) enum Target<K: RateLimitKey, C: RateLimitClock> {
+    Keyed(Arc<KeyedLimiter<K, C>>, K),
+    Shared(Arc<DirectLimiter<C>>),
+}
+
+impl<K: RateLimitKey, C: RateLimitClock> Target<K, C> {
+    fn check(&self, cost: NonZeroU32) -> Result<(), Rejection<C>> {
+        let decision = match self {
+            Self::Keyed(limiter, key) => limiter.check_key_n(key, cost),
+            Self::Shared(limiter) => limiter.check_n(cost),
+        };
+        match decision {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(negative)) => Err(Rejection::Limited(negative)),
+            Err(InsufficientCapacity(capacity)) => Err(Rejection::ExceedsCapacity(capacity)),
+        }
+    }
+
+    /// Returns how many cells of `quota` this target has left and, unless it is full, how
+    /// long until it is, without consuming any.
+    pub(crate) fn state(&self, quota: Quota) -> (u32, Option<Negative<C>>) {
+        let burst = quota.burst_size();
+        peek(|| {
+            let full = match self.check(burst) {
+                Err(Rejection::Limited(negative)) => negative,
+                _ => return (burst.get(), None),
+            };
+            // Search the largest cost still allowed, which is below the burst size.
+            let (mut allowed, mut rejected) = (0, burst.get());
+            while rejected - allowed > 1 {
+                let cost = allowed + (rejected - allowed) / 2;
+                match NonZeroU32::new(cost).map(|cost| self.check(cost)) {
+                    Some(Ok(())) => allowed = cost,
+                    _ => rejected = cost,
+                }
+            }
+            (allowed, Some(full))
+        })
+    }
+}
+
+/// Why a request was not allowed.
+#[derive(Debug)]
+pub(crate) enum Rejection<C: RateLimitClock> {
+    /// The request has to wait.
+    Limited(Negative<C>),
+    /// The request costs more than the burst size of a limiter, which is given.
+    ExceedsCapacity(u32),
+}
+
+/// Counts a request costing `cost` cells against every one of `targets`, or none of them
+/// if any rejects it.
+///
+/// On rejection, returns the index of the rejecting target with its rejection, preferring
+/// a request exceeding a capacity, then the rejection with the longest wait. `lock`
+/// serializes every check, even of a single target, so that no target is consumed by
+/// another request between testing and updating them.
+pub(crate) fn check_all<K: RateLimitKey, C: RateLimitClock>(
+    targets: &[Target<K, C>],
+    cost: NonZeroU32,
+    lock: &Mutex<()>,
+) -> Result<(), (usize, Rejection<C>)> {
+    let _lock = lock.lock().unwrap_or_else(PoisonError::into_inner);
+    if let [target] = targets {
+        return target.check(cost).map_err(|rejection| (0, rejection));
+    }
+    let rejection = peek(|| {
+        targets
+            .iter()
+            .enumerate()
+            .filter_map(|(index, target)| Some((index, target.check(cost).err()?)))
+            .max_by_key(|(_, rejection)| match rejection {
+                Rejection::Limited(negative) => (false, Some(negative.earliest_possible())),
+                Rejection::ExceedsCapacity(_) => (true, None),
+            })
+    });
+    if let Some(rejection) = rejection {
+        return Err(rejection);
+    }
+    targets
+        .iter()
+        .enumerate()
+        .try_for_each(|(index, target)| target.check(cost).map_err(|rejection| (index, rejection)))
+}
+
+#[derive(Debug)]
+struct Bucket<K: RateLimitKey, C: RateLimitClock> {
+    quotas: Vec<Quota>,
+    /// One limiter per quota, each of which has to allow a request.
+    limiters: Vec<Limiter<K, C>>,
+    shared: bool,
+    /// The name of the group this bucket is shared with, if any.
+    group: Option<String>,
+}
+
+impl<K: RateLimitKey, C: RateLimitClock> Bucket<K, C> {
+    fn new(quotas: Vec<Quota>, shared: bool, clock: &C) -> Self {
+        let limiters = quotas
+            .iter()
+            .map(|&quota| {
+                if shared {
+                    Limiter::shared(quota, clock)
+                } else {
+                    Limiter::keyed(quota, clock)
+                }
+            })
+            .collect();
+        Self {
+            quotas,
+            limiters,
+            shared,
+            group: None,
+        }
+    }
+}
+
+impl<K: RateLimitKey, C: RateLimitClock> Clone for Bucket<K, C> {
+    fn clone(&self) -> Self {
+        Self {
+            quotas: self.quotas.clone(),
+            limiters: self.limiters.clone(),
+            shared: self.shared,
+            group: self.group.clone(),
+        }
+    }
+}
+
+/// The quotas of a middleware: some for every configured host, host pattern or group of
+/// hosts and a default for all others, plus the layers enforced on top of them, all
+/// measuring time with one clock.
+#[derive(Debug)]
+pub(crate) struct Rules<K: RateLimitKey, C: RateLimitClock> {
+    default: Bucket<K, C>,
+    hosts: HashMap<String, Bucket<K, C>>,
+    /// Sorted from the most to the least specific pattern.
+    patterns: Vec<(String, HostPattern, Bucket<K, C>)>,
+    layers: Vec<(Layer, Limiter<K, C>)>,
+    clock: C,
+}
+
+impl<K: RateLimitKey, C: RateLimitClock> Rules<K, C> {
+    pub(crate) fn new(default: Quota, clock: C) -> Self {
+        Self {
+            default: Bucket::new(vec![default], false, &clock),
+            hosts: HashMap::new(),
+            patterns: Vec::new(),
+            layers: Vec::new(),
+            clock,
+        }
+    }
+
+    /// Returns the default quotas, of which there is at least one.
+    pub(crate) fn default_quotas(&self) -> &[Quota] {
+        &self.default.quotas
+    }
+
+    pub(crate) fn set_default_quotas(&mut self, quotas: Vec<Quota>) {
+        self.default = Bucket::new(quotas, self.default.shared, &self.clock);
+    }
+
+    /// Makes every request that no other rule applies to draw from one bucket.
+    pub(crate) fn share_default(&mut self) {
+        self.default = Bucket::new(self.default.quotas.clone(), true, &self.clock);
+    }
+
+    pub(crate) fn insert(&mut self, host: &str, quotas: Vec<Quota>) {
+        self.insert_bucket(host, Bucket::new(quotas, false, &self.clock));
+    }
+
+    /// Makes every host in `hosts` draw from one bucket, replacing the group `name`.
+    pub(crate) fn insert_group<'a>(
+        &mut self,
+        name: &str,
+        hosts: impl IntoIterator<Item = &'a str>,
+        quotas: Vec<Quota>,
+    ) {
+        self.remove_group(name);
+        let bucket = Bucket {
+            group: Some(name.to_owned()),
+            ..Bucket::new(quotas, true, &self.clock)
+        };
+        for host in hosts {
+            self.insert_bucket(host, bucket.clone());
+        }
+    }
+
+    fn insert_bucket(&mut self, host: &str, bucket: Bucket<K, C>) {
+        let host = host.to_ascii_lowercase();
+        if !HostPattern::is_pattern(&host) {
+            self.hosts.insert(host, bucket);
+            return;
+        }
+        self.patterns.retain(|(pattern, ..)| *pattern != host);
+        let pattern = HostPattern::new(&host);
+        self.patterns.push((host, pattern, bucket));
+        self.patterns
+            .sort_by_key(|(_, pattern, _)| pattern.specificity());
+    }
+
+    pub(crate) fn add_layer(&mut self, layer: Layer) {
+        let limiter = if layer.is_per_key() {
+            Limiter::keyed(layer.quota(), &self.clock)
+        } else {
+            Limiter::shared(layer.quota(), &self.clock)
+        };
+        self.layers.push((layer, limiter));
+    }
+
+    pub(crate) fn remove_group(&mut self, name: &str) -> bool {
+        let in_group = |bucket: &Bucket<K, C>| bucket.group.as_deref() == Some(name);
+        let len = self.hosts.len() + self.patterns.len();
+        self.hosts.retain(|_, bucket| !in_group(bucket));
+        self.patterns.retain(|(.., bucket)| !in_group(bucket));
+        self.hosts.len() + self.patterns.len() != len
+    }
+
+    pub(crate) fn remove(&mut self, host: &str) -> bool {
+        let host = host.to_ascii_lowercase();
+        let patterns = self.patterns.len();
+        self.patterns.retain(|(pattern, ..)| *pattern != host);
+        self.hosts.remove(&host).is_some() || self.patterns.len() != patterns
+    }
+
+    /// Returns the same rules with fresh limiters for another type of key, measuring
+    /// time with `clock`.
+    pub(crate) fn rekey<L: RateLimitKey, D: RateLimitClock>(&self, clock: D) -> Rules<L, D> {
+        let mut rules = Rules {
+            default: Bucket::new(self.default.quotas.clone(), self.default.shared, &clock),
+            hosts: HashMap::new(),
+            patterns: Vec::new(),
+            layers: Vec::new(),
+            clock,
+        };
+        let mut groups: HashMap<&str, (&[Quota], Vec<&str>)> = HashMap::new();
+        let patterns = self.patterns.iter().map(|(host, _, bucket)| (host, bucket));
+        for (host, bucket) in self.hosts.iter().chain(patterns) {
+            match &bucket.group {
+                Some(name) => groups
+                    .entry(name)
+                    .or_insert_with(|| (&bucket.quotas, Vec::new()))
+                    .1
+                    .push(host),
+                None => rules.insert(host, bucket.quotas.clone()),
+            }
+        }
+        for (name, (quotas, hosts)) in groups {
+            rules.insert_group(name, hosts, quotas.to_vec());
+        }
+        for (layer, _) in &self.layers {
+            rules.add_layer(layer.clone());
+        }
+        rules
+    }
+
+    /// Returns the limiters for `req`: the limiters for its host, found by trying its
+    /// origin, then host and port, then host, then the host patterns, followed by the
+    /// limiters of the layers applying to it.
+    ///
+    /// For requests to hosts without their own quota, `adaptive` returns the limiter
+    /// replacing the one of the first default quota.
+    pub(crate) fn limiters(
+        &self,
+        req: &Request,
+        adaptive: Option<impl FnOnce() -> Arc<DirectLimiter<C>>>,
+    ) -> Vec<Limiter<K, C>> {
+        self.select(self.find(req.url()), adaptive, |layer| {
+            layer.applies_to(req)
+        })
+    }
+
+    /// Returns the limiters for permits taken without a request: the default limiters,
+    /// of which `adaptive` replaces the first, followed by the limiters of the layers
+    /// applying to every request.
+    pub(crate) fn default_limiters(
+        &self,
+        adaptive: Option<impl FnOnce() -> Arc<DirectLimiter<C>>>,
+    ) -> Vec<Limiter<K, C>> {
+        self.select(None, adaptive, Layer::applies_to_all)
+    }
+
+    fn select(
+        &self,
+        host: Option<&Bucket<K, C>>,
+        adaptive: Option<impl FnOnce() -> Arc<DirectLimiter<C>>>,
+        applies: impl Fn(&Layer) -> bool,
+    ) -> Vec<Limiter<K, C>> {
+        let mut limiters = match (host, adaptive) {
+            (Some(host), _) => host.limiters.clone(),
+            (None, Some(adaptive)) => {
+                let mut limiters = vec![Limiter::Shared(adaptive())];
+                limiters.extend(self.default.limiters[1..].iter().cloned());
+                limiters
+            }
+            (None, None) => self.default.limiters.clone(),
+        };
+        let layers = self
+            .layers
+            .iter()
+            .filter(|(layer, _)| applies(layer))
+            .map(|(_, limiter)| limiter.clone());
+        limiters.extend(layers);
+        limiters
+    }
+
+    fn find(&self, url: &Url) -> Option<&Bucket<K, C>> {
+        if self.hosts.is_empty() && self.patterns.is_empty() {
+            return None;
+        }
+        let host = url.host_str()?;
+        self.find_host(url, host).or_else(|| {
+            self.patterns
+                .iter()
+                .find(|(_, pattern, _)| pattern.matches(host))
+                .map(|(.., bucket)| bucket)
+        })
+    }
+
+    fn find_host(&self, url: &Url, host: &str) -> Option<&Bucket<K, C>> {
+        if self.hosts.is_empty() {
+            return None;
+        }
+        self.hosts
+            .get(&url.origin().ascii_serialization())
+            .or_else(|| {
+                let port = url.port_or_known_default()?;
+                self.hosts.get(&format!("{host}:{port}"))
+            })
+            .or_else(|| self.hosts.get(host))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_all, Limiter, Target};
+    use governor::{clock::DefaultClock, Quota};
+    use std::{
+        num::NonZeroU32,
+        sync::{Arc, Barrier, Mutex},
+        thread,
+        time::Duration,
+    };
+
+    #[test]
+    fn never_consumes_targets_of_rejected_requests() {
+        let burst = NonZeroU32::new(1_000_000).unwrap();
+        let keyed_quota = Quota::per_hour(NonZeroU32::new(1).unwrap()).allow_burst(burst);
+        let shared_quota = Quota::with_period(Duration::from_micros(5)).unwrap();
+        let one = NonZeroU32::new(1).unwrap();
+        let clock = DefaultClock::default();
+        let lock = Arc::new(Mutex::new(()));
+        let keyed = Limiter::keyed(keyed_quota, &clock)
+            .target(Some(&"key"))
+            .unwrap();
+        let shared = match Limiter::<&str, _>::shared(shared_quota, &clock) {
+            Limiter::Shared(limiter) => limiter,
+            Limiter::Keyed(_) => unreachable!("shared limiters are shared"),
+        };
+        let barrier = Arc::new(Barrier::new(2));
+        let other = {
+            let (shared, lock, barrier) = (shared.clone(), lock.clone(), barrier.clone());
+            thread::spawn(move || {
+                let targets = [Target::<&str, _>::Shared(shared)];
+                barrier.wait();
+                for _ in 0..200_000 {
+                    let _ = check_all(&targets, one, &lock);
+                }
+            })
+        };
+        let targets = [keyed, Target::Shared(shared)];
+        barrier.wait();
+        let allowed = (0..200_000)
+            .filter(|_| check_all(&targets, one, &lock).is_ok())
+            .count();
+        other.join().unwrap();
+        let (remaining, _) = targets[0].state(keyed_quota);
+        assert_eq!((burst.get() - remaining) as usize, allowed);
+    }
+}
@@ -0,0 +1,52 @@
+use governor::{nanos::Nanos, state::StateStore};
+use std::{cell::Cell, convert::Infallible};
+
+thread_local! {
+    static PEEKING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Runs `f`, during which every [`PeekableState`] on this thread computes its decisions
+/// without recording them.
+///
+/// This lets several limiters be tested before any of them is updated, so that no
+/// limiter is consumed when another one would reject the request.
+pub(crate) fn peek<R>(f: impl FnOnce() -> R) -> R {
+    struct Reset;
+
+    impl Drop for Reset {
+        fn drop(&mut self) {
+            PEEKING.with(|peeking| peeking.set(false));
+        }
+    }
+
+    PEEKING.with(|peeking| peeking.set(true));
+    let _reset = Reset;
+    f()
+}
+
+/// A state store that leaves its state untouched while [`peek`]ing.
+#[derive(Debug, Default)]
+pub(crate) struct PeekableState<S>(S);
+
+impl<S: StateStore> StateStore for PeekableState<S> {
+    type Key = S::Key;
+
+    fn measure_and_replace<T, F, E>(&self, key: &Self::Key, f: F) -> Result<T, E>
+    where
+        F: Fn(Option<Nanos>) -> Result<(T, Nanos), E>,
+    {
+        if !PEEKING.with(Cell::get) {
+            return self.0.measure_and_replace(key, f);
+        }
+        // Turning every decision into an error stops the state from being replaced.
+        let decision = self
+            .0
+            .measure_and_replace(key, |tat| -> Result<(Infallible, Nanos), _> {
+                Err(f(tat).map(|(result, _)| result))
+            });
+        match decision {
+            Ok(never) => match never {},
+            Err(decision) => decision,
+        }
+    }
+}